@@ -0,0 +1,169 @@
+//! The kernel heap's free list, kept apart from the kernel so it builds and
+//! runs its tests on the host
+//!
+//! Free blocks form a list sorted by address, and neighbours are merged when a
+//! block is freed. Every block starts with a node header. Allocations are cut
+//! from the top of the first free block they fit in.
+#![no_std]
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+pub const CHUNK: usize = 16; // 16 byte chunks
+pub const NODE_SIZE_ALIGNED: usize = align_up(size_of::<Node>(), CHUNK);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+struct NodePointer(NonNull<Node>);
+
+impl NodePointer {
+    // the caller makes sure `addr` is a block inside the heap
+    unsafe fn at(addr: usize) -> Self {
+        Self(NonNull::new_unchecked(addr as *mut Node))
+    }
+
+    fn addr(&self) -> usize {
+        self.0.as_ptr() as usize
+    }
+
+    fn end(&self) -> usize {
+        self.addr() + self.size()
+    }
+
+    fn size(&self) -> usize {
+        unsafe { (*self.0.as_ptr()).size }
+    }
+
+    fn set_size(&self, size: usize) -> Self {
+        unsafe { (*self.0.as_ptr()).size = size }
+        *self
+    }
+
+    fn next(&self) -> Option<NodePointer> {
+        unsafe { (*self.0.as_ptr()).next }
+    }
+
+    fn set_next(&self, next: Option<NodePointer>) -> Self {
+        unsafe { (*self.0.as_ptr()).next = next }
+        *self
+    }
+}
+
+// `size` covers the whole block, header included
+struct Node {
+    size: usize,
+    next: Option<NodePointer>,
+}
+
+pub struct HeapAllocator {
+    head: Option<NodePointer>,
+}
+
+unsafe impl Send for HeapAllocator {}
+
+impl HeapAllocator {
+    pub const fn new() -> Self {
+        Self { head: None }
+    }
+
+    /// Hands the allocator `heap_size` bytes at `heap_start`.
+    ///
+    /// # Safety
+    /// The memory must be valid, unused by anything else and live forever.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let start = align_up(heap_start, CHUNK);
+        let end = (heap_start + heap_size) & !(CHUNK - 1);
+        self.head = None;
+        if end >= start + NODE_SIZE_ALIGNED {
+            self.head = Some(NodePointer::at(start).set_size(end - start).set_next(None));
+        }
+    }
+
+    /// First fit, null if nothing fits.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = align_up(layout.size().max(1), CHUNK);
+        let align = layout.align().max(CHUNK);
+
+        let mut previous: Option<NodePointer> = None;
+        let mut current = self.head;
+        while let Some(free_node) = current {
+            if let Some(start) = fit(free_node, size, align) {
+                let end = free_node.end();
+                if start == free_node.addr() {
+                    // the whole block goes, unlink it
+                    self.set_next_of(previous, free_node.next());
+                } else {
+                    free_node.set_size(start - free_node.addr());
+                }
+                let block = unsafe { NodePointer::at(start) }.set_size(end - start).set_next(None);
+                return (block.addr() + NODE_SIZE_ALIGNED) as *mut u8;
+            }
+            previous = current;
+            current = free_node.next();
+        }
+        ptr::null_mut()
+    }
+
+    /// Gives back a block from `allocate`.
+    ///
+    /// # Safety
+    /// `ptr` must have come from `allocate` on this allocator and not be freed already.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, _layout: Layout) {
+        let block = NodePointer::at(ptr as usize - NODE_SIZE_ALIGNED);
+
+        // find the free blocks on either side
+        let mut previous: Option<NodePointer> = None;
+        let mut next = self.head;
+        while let Some(node) = next.filter(|node| node.addr() < block.addr()) {
+            previous = Some(node);
+            next = node.next();
+        }
+
+        block.set_next(next);
+        self.set_next_of(previous, Some(block));
+        if let Some(next) = next.filter(|next| block.end() == next.addr()) {
+            block.set_size(block.size() + next.size()).set_next(next.next());
+        }
+        if let Some(previous) = previous.filter(|previous| previous.end() == block.addr()) {
+            previous.set_size(previous.size() + block.size()).set_next(block.next());
+        }
+    }
+
+    /// Bytes in free blocks, headers included.
+    pub fn free(&self) -> usize {
+        self.free_blocks().map(|(_, size)| size).sum()
+    }
+
+    /// (address, size) of every free block, lowest address first.
+    pub fn free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        core::iter::successors(self.head, |node| node.next()).map(|node| (node.addr(), node.size()))
+    }
+
+    fn set_next_of(&mut self, node: Option<NodePointer>, next: Option<NodePointer>) {
+        match node {
+            Some(node) => { node.set_next(next); },
+            None => self.head = next,
+        }
+    }
+}
+
+impl Default for HeapAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Where the block for an allocation would start in `free_node`, as high up as
+// alignment allows. What's left below has to be empty or big enough for a
+// header, which it always is while headers are a single chunk.
+fn fit(free_node: NodePointer, size: usize, align: usize) -> Option<usize> {
+    let data = free_node.end().checked_sub(size)? & !(align - 1);
+    let start = data.checked_sub(NODE_SIZE_ALIGNED)?;
+    let left = start.checked_sub(free_node.addr())?;
+    (left == 0 || left >= NODE_SIZE_ALIGNED).then_some(start)
+}
+
+pub const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,195 @@
+//! Runs the allocator over a heap borrowed from the host, and checks every
+//! block it hands out against a model of what should be live.
+use std::alloc::{alloc, dealloc, Layout};
+use std::collections::BTreeMap;
+use allocator::{HeapAllocator, CHUNK, NODE_SIZE_ALIGNED};
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+struct Heap {
+    memory: *mut u8,
+    allocator: HeapAllocator,
+    live: BTreeMap<usize, (Layout, u8)>, // address -> (layout, fill byte)
+}
+
+impl Heap {
+    fn new(size: usize) -> Heap {
+        let memory = unsafe { alloc(Layout::from_size_align(size, 4096).unwrap()) };
+        let mut allocator = HeapAllocator::new();
+        unsafe { allocator.init(memory as usize, size) };
+        Heap { memory, allocator, live: BTreeMap::new() }
+    }
+
+    fn start(&self) -> usize {
+        self.memory as usize
+    }
+
+    // checks the block against the model and fills it, so later overlaps or
+    // header writes into it show up as changed bytes
+    fn allocate(&mut self, size: usize, align: usize, fill: u8) -> Option<usize> {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = self.allocator.allocate(layout);
+        if ptr.is_null() {
+            return None;
+        }
+        let addr = ptr as usize;
+        assert_eq!(addr % align, 0, "{:?} misaligned at {:#x}", layout, addr);
+        assert!(addr >= self.start() + NODE_SIZE_ALIGNED && addr + size <= self.start() + HEAP_SIZE, "{:#x} outside the heap", addr);
+        if let Some((&below, &(below_layout, _))) = self.live.range(..addr).next_back() {
+            assert!(below + below_layout.size() <= addr, "{:#x} overlaps the block at {:#x}", addr, below);
+        }
+        if let Some((&above, _)) = self.live.range(addr..).next() {
+            assert!(addr + size <= above, "{:#x} overlaps the block at {:#x}", addr, above);
+        }
+        unsafe { ptr.write_bytes(fill, size) };
+        self.live.insert(addr, (layout, fill));
+        Some(addr)
+    }
+
+    fn deallocate(&mut self, addr: usize) {
+        let (layout, fill) = self.live.remove(&addr).unwrap();
+        let contents = unsafe { std::slice::from_raw_parts(addr as *const u8, layout.size()) };
+        assert!(contents.iter().all(|&byte| byte == fill), "block at {:#x} was overwritten", addr);
+        unsafe { self.allocator.deallocate(addr as *mut u8, layout) };
+        self.check_free_list();
+    }
+
+    // sorted, merged, inside the heap and clear of everything live
+    fn check_free_list(&self) {
+        let mut last_end = 0;
+        for (addr, size) in self.allocator.free_blocks() {
+            assert!(addr > last_end || last_end == 0, "free list unsorted or unmerged at {:#x}", addr);
+            assert!(size >= NODE_SIZE_ALIGNED && size % CHUNK == 0);
+            assert!(addr >= self.start() && addr + size <= self.start() + HEAP_SIZE);
+            if let Some((&below, &(layout, _))) = self.live.range(..addr + size).next_back() {
+                assert!(below + layout.size() <= addr, "free block at {:#x} overlaps live {:#x}", addr, below);
+            }
+            last_end = addr + size;
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory, Layout::from_size_align(HEAP_SIZE, 4096).unwrap()) };
+    }
+}
+
+// xorshift, good enough to shuffle allocation patterns reproducibly
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+#[test]
+fn allocate_and_free_in_order() {
+    let mut heap = Heap::new(HEAP_SIZE);
+    let blocks: Vec<usize> = (1..=32).map(|size| heap.allocate(size * 7, 8, size as u8).unwrap()).collect();
+    for addr in blocks {
+        heap.deallocate(addr);
+    }
+    assert_eq!(heap.allocator.free(), HEAP_SIZE);
+}
+
+#[test]
+fn freeing_in_any_order_merges_back_into_one_block() {
+    let mut heap = Heap::new(HEAP_SIZE);
+    let mut blocks: Vec<usize> = (0..64).map(|index| heap.allocate(100, 16, index).unwrap()).collect();
+    let mut rng = Rng(7);
+    while !blocks.is_empty() {
+        let addr = blocks.swap_remove(rng.below(blocks.len()));
+        heap.deallocate(addr);
+    }
+    assert_eq!(heap.allocator.free_blocks().count(), 1);
+    assert_eq!(heap.allocator.free(), HEAP_SIZE);
+}
+
+#[test]
+fn alignment_is_honoured() {
+    let mut heap = Heap::new(HEAP_SIZE);
+    for align in [1, 2, 8, 16, 32, 64, 256, 1024, 4096] {
+        for size in [1, 24, 100, 4096] {
+            heap.allocate(size, align, align as u8).unwrap();
+        }
+    }
+    let live: Vec<usize> = heap.live.keys().copied().collect();
+    for addr in live {
+        heap.deallocate(addr);
+    }
+    assert_eq!(heap.allocator.free(), HEAP_SIZE);
+}
+
+#[test]
+fn exhaustion_returns_null_and_recovers() {
+    let mut heap = Heap::new(HEAP_SIZE);
+    let mut count = 0;
+    while heap.allocate(1000, 8, count as u8).is_some() {
+        count += 1;
+    }
+    assert!(count >= HEAP_SIZE / (1000 + NODE_SIZE_ALIGNED + CHUNK) - 1, "only {} blocks fit", count);
+    assert!(heap.allocate(HEAP_SIZE, 8, 0).is_none());
+
+    let live: Vec<usize> = heap.live.keys().copied().collect();
+    for addr in live {
+        heap.deallocate(addr);
+    }
+    // with everything merged again, one block the size of almost the whole heap fits
+    assert!(heap.allocate(HEAP_SIZE - NODE_SIZE_ALIGNED, 8, 1).is_some());
+    assert!(heap.allocate(1, 1, 2).is_none());
+}
+
+#[test]
+fn an_exact_fit_takes_the_whole_block() {
+    let mut heap = Heap::new(HEAP_SIZE);
+    let first = heap.allocate(HEAP_SIZE / 2 - NODE_SIZE_ALIGNED, 16, 1).unwrap();
+    let second = heap.allocate(HEAP_SIZE / 2 - NODE_SIZE_ALIGNED, 16, 2).unwrap();
+    assert_eq!(heap.allocator.free(), 0);
+    heap.deallocate(first);
+    heap.deallocate(second);
+    assert_eq!(heap.allocator.free(), HEAP_SIZE);
+}
+
+#[test]
+fn tiny_heap_has_room_for_nothing() {
+    let mut allocator = HeapAllocator::new();
+    let mut memory = [0u8; 8];
+    unsafe { allocator.init(memory.as_mut_ptr() as usize, memory.len()) };
+    assert!(allocator.allocate(Layout::new::<u8>()).is_null());
+}
+
+#[test]
+fn randomized_stress_against_the_model() {
+    for seed in 1..=16u64 {
+        let mut heap = Heap::new(HEAP_SIZE);
+        let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        for step in 0..5000 {
+            if heap.live.is_empty() || rng.below(100) < 55 {
+                let size = match rng.below(10) {
+                    0 => rng.below(8 * 1024) + 1,
+                    _ => rng.below(256) + 1,
+                };
+                let align = 1 << rng.below(9);
+                heap.allocate(size, align, step as u8);
+            } else {
+                let index = rng.below(heap.live.len());
+                let addr = *heap.live.keys().nth(index).unwrap();
+                heap.deallocate(addr);
+            }
+        }
+        let live: Vec<usize> = heap.live.keys().copied().collect();
+        for addr in live {
+            heap.deallocate(addr);
+        }
+        assert_eq!(heap.allocator.free(), HEAP_SIZE, "seed {}", seed);
+    }
+}
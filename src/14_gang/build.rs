@@ -0,0 +1,25 @@
+// `cargo test` links the kernel itself instead of going through the Justfile,
+// so the boot code is assembled here and linked in with the kernel's script.
+// Plain builds produce a static library and never look at these.
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    for name in ["boot", "trampoline"] {
+        let source = format!("{}/src/asm/{}.asm", manifest_dir, name);
+        let object = format!("{}/{}.o", out_dir, name);
+        let status = Command::new("nasm").args(["-felf64", &source, "-o", &object]).status().expect("nasm not found");
+        assert!(status.success(), "nasm failed on {}", source);
+        println!("cargo:rustc-link-arg={}", object);
+        println!("cargo:rerun-if-changed={}", source);
+    }
+
+    for arg in ["-nostdlib", "-static", "-no-pie", "-Wl,-n,--gc-sections"] {
+        println!("cargo:rustc-link-arg={}", arg);
+    }
+    println!("cargo:rustc-link-arg=-Wl,-T,{}/linker.ld", manifest_dir);
+    println!("cargo:rerun-if-changed=linker.ld");
+}
@@ -0,0 +1,97 @@
+//! Just enough ACPI to find the other CPUs
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use multiboot2::BootInformation;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+pub struct Madt {
+    pub local_apic_address: usize,
+    pub apic_ids: Vec<u8>, // enabled or online capable processors
+}
+
+// maps the table in case the firmware put it above the identity mapped area
+unsafe fn table(address: usize) -> Option<SdtHeader> {
+    crate::memory::map_mmio(address, size_of::<SdtHeader>());
+    let header = read_unaligned(address as *const SdtHeader);
+    if (header.length as usize) < size_of::<SdtHeader>() {
+        return None;
+    }
+    crate::memory::map_mmio(address, header.length as usize);
+
+    let bytes = core::slice::from_raw_parts(address as *const u8, header.length as usize);
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    (sum == 0).then_some(header)
+}
+
+fn find_table(boot_info: &BootInformation, signature: &[u8; 4]) -> Option<usize> {
+    // the xsdt has 64 bit pointers, the rsdt 32 bit ones
+    let (root, pointer_size) = match (boot_info.rsdp_v2_tag(), boot_info.rsdp_v1_tag()) {
+        (Some(rsdp), _) if rsdp.xsdt_address() != 0 => (rsdp.xsdt_address(), 8),
+        (_, Some(rsdp)) => (rsdp.rsdt_address(), 4),
+        _ => return None,
+    };
+
+    let header = unsafe { table(root) }?;
+    let entries = (header.length as usize).checked_sub(size_of::<SdtHeader>())? / pointer_size;
+    (0..entries)
+        .map(|i| root + size_of::<SdtHeader>() + i * pointer_size)
+        .map(|entry| unsafe {
+            match pointer_size {
+                8 => read_unaligned(entry as *const u64) as usize,
+                _ => read_unaligned(entry as *const u32) as usize,
+            }
+        })
+        .find(|&address| unsafe { table(address) }.is_some_and(|header| &header.signature == signature))
+}
+
+pub fn madt(boot_info: &BootInformation) -> Option<Madt> {
+    let address = find_table(boot_info, b"APIC")?;
+    let header = unsafe { read_unaligned(address as *const SdtHeader) };
+    let end = address + header.length as usize;
+    if end < address + size_of::<SdtHeader>() + 8 {
+        return None; // not even room for the local apic address
+    }
+
+    let mut entry = address + size_of::<SdtHeader>();
+    let mut madt = Madt {
+        local_apic_address: unsafe { read_unaligned(entry as *const u32) } as usize,
+        apic_ids: Vec::new(),
+    };
+    entry += 8; // local apic address and flags
+
+    while entry + 2 <= end {
+        let (kind, length) = unsafe { (*(entry as *const u8), *((entry + 1) as *const u8) as usize) };
+        if length < 2 {
+            break;
+        }
+        match kind {
+            // processor local apic
+            0 => {
+                let apic_id = unsafe { *((entry + 3) as *const u8) };
+                let flags = unsafe { read_unaligned((entry + 4) as *const u32) };
+                if flags & 0b11 != 0 {
+                    madt.apic_ids.push(apic_id);
+                }
+            },
+            // local apic address override
+            5 => madt.local_apic_address = unsafe { read_unaligned((entry + 4) as *const u64) } as usize,
+            _ => {},
+        }
+        entry += length;
+    }
+    Some(madt)
+}
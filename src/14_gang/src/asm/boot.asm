@@ -1,7 +1,8 @@
 global start
 extern rust_main
 
-section .text
+; its own section, which linker.ld puts first whatever order the objects are linked in
+section .multiboot_header progbits alloc noexec nowrite align=8
 mb_start:
     dd 0xe85250d6                 ; Magic number (Multiboot 2)
     dd 0                          ; Architecture (0 for i386 protected mode)
@@ -12,6 +13,7 @@ mb_start:
     dd 8                          ; End tag size
 mb_end:
 
+section .text
 bits 32
 start:
     mov esp, stack_top            ; Set stack pointer
@@ -72,6 +74,7 @@ long_mode:
     mov fs, ax
     mov gs, ax
 
+    xor rbp, rbp                  ; End of the frame pointer chain
     call rust_main                ; Go to rust code
     hlt
 
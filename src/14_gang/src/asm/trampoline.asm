@@ -0,0 +1,79 @@
+; Application processor startup code. The BSP copies everything between
+; trampoline_start and trampoline_end to TRAMPOLINE and fills in
+; trampoline_data before sending the startup IPI.
+global trampoline_start
+global trampoline_end
+global trampoline_data
+
+TRAMPOLINE equ 0x8000
+%define ABS(label) (TRAMPOLINE + (label) - trampoline_start)
+
+section .text
+bits 16
+trampoline_start:
+    cli
+    cld
+    xor ax, ax                    ; Real mode segments at 0, so
+    mov ds, ax                    ; ABS() addresses work as is
+    lgdt [ABS(gdt.pointer)]       ; Load temporary GDT
+    mov eax, cr0
+    or eax, 1                     ; Set PE bit (protected mode)
+    mov cr0, eax
+    jmp dword gdt.code32:ABS(protected_mode)
+
+bits 32
+protected_mode:
+    mov ax, gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov eax, cr4                  ; CR4
+    or eax, 1 << 5                ; Set PAE bit
+    mov cr4, eax
+    mov eax, [ABS(trampoline_data.p4)]
+    mov cr3, eax                  ; Same page tables as the BSP
+    mov ecx, 0xC0000080           ; EFER MSR address
+    rdmsr                         ; Read EFER
+    or eax, 1 << 8 | 1 << 11      ; Set LME and NXE bits
+    wrmsr                         ; Write EFER
+    mov eax, cr0                  ; Get current CR0
+    or eax, 1 << 31               ; Set PG bit (paging)
+    mov cr0, eax
+    jmp gdt.code64:ABS(long_mode)
+
+bits 64
+long_mode:
+    xor ax, ax                    ; Zero out segment registers
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [ABS(trampoline_data.stack)]
+    mov rdi, [ABS(trampoline_data.cpu_id)]
+    mov rax, [ABS(trampoline_data.entry)]
+    xor rbp, rbp                  ; End of the frame pointer chain
+    call rax                      ; Go to rust code, never returns
+    hlt
+
+align 8
+gdt:
+    dq 0                          ; Null seg
+.code32: equ $ - gdt
+    dq 0x00CF9A000000FFFF         ; 32 bit code seg
+.data: equ $ - gdt
+    dq 0x00CF92000000FFFF         ; 32 bit data seg
+.code64: equ $ - gdt
+    dq 0x20980000000000           ; 64 bit code seg
+.pointer:
+    dw $ - gdt - 1                ; Size as 16-bit
+    dd ABS(gdt)                   ; Base address
+
+align 8
+trampoline_data:
+.p4:     dq 0                     ; Physical address of the P4 table
+.stack:  dq 0                     ; Top of the stack for this CPU
+.entry:  dq 0                     ; Rust entry point
+.cpu_id: dq 0                     ; Passed to the entry point
+trampoline_end:
@@ -0,0 +1,186 @@
+//! Frame pointer backtraces, resolved against the kernel's own symbol table
+//!
+//! The target keeps frame pointers, so every frame starts with the caller's rbp
+//! followed by the return address. GRUB loads the section headers and the
+//! symbol and string tables along with the kernel, which is where names come from.
+use core::arch::asm;
+use core::fmt;
+use spin::Once;
+use multiboot2::{BootInformation, ElfSectionType};
+use crate::memory::paging;
+
+const MAX_FRAMES: usize = 32;
+const SYMBOL_SIZE: usize = 24;
+const STT_FUNC: u8 = 2;
+
+static SYMBOLS: Once<Symbols> = Once::new();
+
+struct Symbols {
+    symtab: &'static [u8],
+    strtab: &'static [u8],
+}
+
+/// Finds the symbol table, if GRUB loaded one. Nothing allocated, so this can
+/// run before the heap exists.
+pub fn init(boot_info: &BootInformation) {
+    let Some(sections) = boot_info.elf_sections() else {
+        return;
+    };
+    let (mut symtab, mut strtab) = (None, None);
+    for section in sections.filter(|section| section.start_address() != 0) {
+        let bytes = unsafe { core::slice::from_raw_parts(section.start_address() as *const u8, section.size() as usize) };
+        match (section.section_type(), section.name()) {
+            (ElfSectionType::LinkerSymbolTable, _) => symtab = Some(bytes),
+            (ElfSectionType::StringTable, Ok(".strtab")) => strtab = Some(bytes),
+            _ => {}
+        }
+    }
+    if let (Some(symtab), Some(strtab)) = (symtab, strtab) {
+        SYMBOLS.call_once(|| Symbols { symtab, strtab });
+    }
+}
+
+/// End of the loaded symbol and string tables, which nothing else may allocate.
+pub fn symbols_end(boot_info: &BootInformation) -> usize {
+    boot_info.elf_sections().into_iter().flatten()
+        .filter(|section| !section.is_allocated() && section.start_address() != 0)
+        .map(|section| section.end_address() as usize)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Calls `f` with each return address, starting with whoever called `trace`.
+#[inline(never)]
+pub fn trace(f: impl FnMut(usize)) {
+    let rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    walk(rbp, f);
+}
+
+/// Follows the rbp chain up from `rbp`. The boot code zeroes rbp before calling
+/// into Rust, so the chain ends there. Inside an exception handler the slot
+/// after rbp holds the interrupted rip, or the error code for faults with one.
+pub fn walk(mut rbp: usize, mut f: impl FnMut(usize)) {
+    let p4 = paging::active_p4();
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || paging::translate(p4, rbp).is_none() || paging::translate(p4, rbp + 8).is_none() {
+            break;
+        }
+        let (caller_rbp, return_address) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+        // callers are further up the stack, anything else is garbage
+        if caller_rbp <= rbp {
+            break;
+        }
+        rbp = caller_rbp;
+    }
+}
+
+/// Prints a backtrace of the caller to the screen and serial.
+pub fn print() {
+    println!("Backtrace:");
+    trace(|address| println!("  {}", Symbolized(address)));
+}
+
+/// The function containing `address` and how far into it the address is.
+pub fn resolve(address: usize) -> Option<(&'static str, usize)> {
+    let symbols = SYMBOLS.r#try()?;
+    symbols.symtab.chunks_exact(SYMBOL_SIZE)
+        .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+        .filter_map(|symbol| {
+            let value = u64::from_le_bytes(symbol[8..16].try_into().unwrap()) as usize;
+            let size = u64::from_le_bytes(symbol[16..24].try_into().unwrap()) as usize;
+            (value <= address && address < value + size.max(1)).then_some((symbol, value))
+        })
+        .find_map(|(symbol, value)| {
+            let name = u32::from_le_bytes(symbol[0..4].try_into().unwrap()) as usize;
+            Some((symbols.name(name)?, address - value))
+        })
+}
+
+impl Symbols {
+    fn name(&self, offset: usize) -> Option<&'static str> {
+        let bytes = self.strtab.get(offset..)?;
+        let len = bytes.iter().position(|&byte| byte == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// Formats as the address followed by `function+offset`, or `??` if unknown.
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // a return address is just past the call, which may be the last instruction of the function
+        match resolve(self.0.wrapping_sub(1)) {
+            Some((name, offset)) => write!(f, "{:#018x} {}+{:#x}", self.0, Demangled(name), offset + 1),
+            None => write!(f, "{:#018x} ??", self.0),
+        }
+    }
+}
+
+// Legacy Rust mangling: _ZN, length prefixed path segments, a hash segment and E.
+// Anything else is printed as it is. Nothing here allocates, it runs from panics.
+struct Demangled<'a>(&'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+        let mut first = true;
+        while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&digits| digits > 0) {
+            let len: usize = rest[..digits].parse().map_err(|_| fmt::Error)?;
+            let Some(segment) = rest.get(digits..digits + len) else {
+                break;
+            };
+            rest = &rest[digits + len..];
+            let is_hash = segment.len() == 17 && segment.starts_with('h')
+                && segment[1..].bytes().all(|byte| byte.is_ascii_hexdigit());
+            if is_hash && rest == "E" {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_segment(f: &mut fmt::Formatter, segment: &str) -> fmt::Result {
+    // segments that would start with an escape get an extra underscore in front
+    let mut rest = segment.strip_prefix('_').filter(|rest| rest.starts_with('$')).unwrap_or(segment);
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some(escape) = rest.strip_prefix('$').and_then(|after| Some(&after[..after.find('$')?])) {
+            match escape {
+                "LT" => f.write_str("<")?,
+                "GT" => f.write_str(">")?,
+                "RF" => f.write_str("&")?,
+                "BP" => f.write_str("*")?,
+                "C" => f.write_str(",")?,
+                "SP" => f.write_str("@")?,
+                "LP" => f.write_str("(")?,
+                "RP" => f.write_str(")")?,
+                _ => match escape.strip_prefix('u').and_then(|hex| u32::from_str_radix(hex, 16).ok()).and_then(char::from_u32) {
+                    Some(c) => write!(f, "{}", c)?,
+                    None => write!(f, "${}$", escape)?,
+                },
+            }
+            rest = &rest[escape.len() + 2..];
+        } else {
+            let end = rest[1..].find(['$', '.']).map_or(rest.len(), |end| end + 1);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
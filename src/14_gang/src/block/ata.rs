@@ -0,0 +1,246 @@
+//! ATA PIO driver for the two legacy IDE channels
+//!
+//! Commands are issued by polling the status register, and each sector is
+//! waited for on the channel's IRQ (14 or 15) before it's transferred.
+use log::info;
+use alloc::sync::Arc;
+use alloc::string::String;
+use crate::sync::{Mutex, Semaphore};
+use crate::util::{inb, inw, outb, outw};
+use super::{BlockDevice, Error, SECTOR_SIZE};
+
+// registers, from the io base
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// status bits
+const ERR: u8 = 1 << 0;
+const DRQ: u8 = 1 << 3;
+const DF: u8 = 1 << 5;
+const BSY: u8 = 1 << 7;
+
+const IDENTIFY: u8 = 0xEC;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const CACHE_FLUSH: u8 = 0xE7;
+const CACHE_FLUSH_EXT: u8 = 0xEA;
+
+const NO_INTERRUPTS: u8 = 1 << 1; // in the device control register
+const TIMEOUT_MS: u32 = 1000;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+struct Channel {
+    io: u16,
+    control: u16,
+    irq: Semaphore,
+    lock: Mutex<()>, // one command at a time
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel::new(0x1F0, 0x3F6),
+    Channel::new(0x170, 0x376),
+];
+
+pub struct Drive {
+    channel: &'static Channel,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    pub model: String,
+}
+
+pub fn init() {
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        if inb(channel.io + STATUS) == 0xFF {
+            continue; // floating bus, nothing attached
+        }
+        for slave in [false, true] {
+            if let Some(drive) = channel.identify(slave) {
+                let name = ["hda", "hdb", "hdc", "hdd"][index * 2 + slave as usize];
+                info!("{}: {} ({} MiB)", name, drive.model, drive.sectors * SECTOR_SIZE as u64 / (1024 * 1024));
+                super::register(name, super::cache::cached(Arc::new(drive)));
+            }
+        }
+        outb(channel.control, 0); // interrupts on from here
+    }
+    crate::interrupts::pic::PICS.lock().unmask(14);
+    crate::interrupts::pic::PICS.lock().unmask(15);
+}
+
+/// Called from the IRQ 14 and 15 handlers. Reading the status acknowledges the interrupt.
+pub fn interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    inb(channel.io + STATUS);
+    channel.irq.release();
+}
+
+impl Channel {
+    const fn new(io: u16, control: u16) -> Channel {
+        Channel { io, control, irq: Semaphore::new(0), lock: Mutex::new(()) }
+    }
+
+    // reading the alternate status takes about 100ns, and the drive wants 400ns after a select
+    fn delay(&self) {
+        for _ in 0..4 {
+            inb(self.control);
+        }
+    }
+
+    fn poll(&self, until: impl Fn(u8) -> bool) -> Result<u8, Error> {
+        let deadline = crate::interrupts::pit::ticks() + crate::interrupts::pit::ms_to_ticks(TIMEOUT_MS);
+        loop {
+            let status = inb(self.control);
+            if status & BSY == 0 && status & (ERR | DF) != 0 {
+                return Err(Error::Device { status, error: inb(self.io + ERROR) });
+            }
+            if until(status) {
+                return Ok(status);
+            }
+            if crate::interrupts::pit::ticks() > deadline {
+                return Err(Error::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn wait_irq(&self) -> Result<(), Error> {
+        if !self.irq.acquire_timeout(TIMEOUT_MS) {
+            return Err(Error::Timeout);
+        }
+        self.poll(|status| status & BSY == 0).map(|_| ())
+    }
+
+    // IDENTIFY is polled with the channel's interrupt turned off (nIEN) and its IRQ still masked
+    fn identify(&'static self, slave: bool) -> Option<Drive> {
+        outb(self.control, NO_INTERRUPTS);
+        outb(self.io + DRIVE, 0xA0 | (slave as u8) << 4);
+        self.delay();
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            outb(self.io + register, 0);
+        }
+        outb(self.io + COMMAND, IDENTIFY);
+        if inb(self.io + STATUS) == 0 {
+            return None; // no drive
+        }
+        self.poll(|status| status & BSY == 0).ok()?;
+        if inb(self.io + LBA_MID) != 0 || inb(self.io + LBA_HIGH) != 0 {
+            return None; // ATAPI or SATA, which don't speak plain ATA
+        }
+        self.poll(|status| status & DRQ != 0).ok()?;
+
+        let mut words = [0u16; 256];
+        for word in words.iter_mut() {
+            *word = inw(self.io + DATA);
+        }
+
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = match lba48 {
+            true => words[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | word as u64),
+            false => (words[61] as u64) << 16 | words[60] as u64,
+        };
+        // the model string has the bytes of each word swapped
+        let model = words[27..47].iter().flat_map(|word| word.to_be_bytes()).map(char::from).collect::<String>();
+
+        Some(Drive { channel: self, slave, lba48, sectors, model: String::from(model.trim()) })
+    }
+}
+
+impl Drive {
+    // selects the drive and sends a command for `count` sectors, at most 256
+    fn command(&self, command: (u8, u8), lba: u64, count: usize) {
+        let io = self.channel.io;
+        let slave = (self.slave as u8) << 4;
+        while self.channel.irq.try_acquire() {} // leftovers from earlier commands
+
+        if self.lba48 && (lba + count as u64 > LBA28_LIMIT || count > 256) {
+            outb(io + DRIVE, 0x40 | slave);
+            self.channel.delay();
+            // high bytes first, the registers are two deep
+            outb(io + SECTOR_COUNT, (count >> 8) as u8);
+            outb(io + LBA_LOW, (lba >> 24) as u8);
+            outb(io + LBA_MID, (lba >> 32) as u8);
+            outb(io + LBA_HIGH, (lba >> 40) as u8);
+            outb(io + SECTOR_COUNT, count as u8);
+            outb(io + LBA_LOW, lba as u8);
+            outb(io + LBA_MID, (lba >> 8) as u8);
+            outb(io + LBA_HIGH, (lba >> 16) as u8);
+            outb(io + COMMAND, command.1);
+        } else {
+            outb(io + DRIVE, 0xE0 | slave | (lba >> 24) as u8 & 0xF);
+            self.channel.delay();
+            outb(io + SECTOR_COUNT, count as u8); // 0 means 256
+            outb(io + LBA_LOW, lba as u8);
+            outb(io + LBA_MID, (lba >> 8) as u8);
+            outb(io + LBA_HIGH, (lba >> 16) as u8);
+            outb(io + COMMAND, command.0);
+        }
+    }
+
+    fn check_range(&self, start: u64, len: usize) -> Result<(), Error> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if len % SECTOR_SIZE != 0 || start.checked_add(count).map_or(true, |end| end > self.sectors) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Drive {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.check_range(start, buffer.len())?;
+        let _lock = self.channel.lock.lock();
+
+        for (index, chunk) in buffer.chunks_mut(256 * SECTOR_SIZE).enumerate() {
+            self.command((READ_SECTORS, READ_SECTORS_EXT), start + index as u64 * 256, chunk.len() / SECTOR_SIZE);
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                self.channel.wait_irq()?;
+                self.channel.poll(|status| status & DRQ != 0)?;
+                for bytes in sector.chunks_mut(2) {
+                    bytes.copy_from_slice(&inw(self.channel.io + DATA).to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buffer: &[u8]) -> Result<(), Error> {
+        self.check_range(start, buffer.len())?;
+        let _lock = self.channel.lock.lock();
+
+        for (index, chunk) in buffer.chunks(256 * SECTOR_SIZE).enumerate() {
+            self.command((WRITE_SECTORS, WRITE_SECTORS_EXT), start + index as u64 * 256, chunk.len() / SECTOR_SIZE);
+            // the first sector goes out without an interrupt, then one comes after each
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                self.channel.poll(|status| status & DRQ != 0)?;
+                for bytes in sector.chunks(2) {
+                    outw(self.channel.io + DATA, u16::from_le_bytes([bytes[0], bytes[1]]));
+                }
+                self.channel.wait_irq()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let _lock = self.channel.lock.lock();
+        let io = self.channel.io;
+        while self.channel.irq.try_acquire() {}
+        outb(io + DRIVE, 0xA0 | (self.slave as u8) << 4);
+        self.channel.delay();
+        outb(io + COMMAND, if self.lba48 { CACHE_FLUSH_EXT } else { CACHE_FLUSH });
+        self.channel.wait_irq()
+    }
+}
@@ -0,0 +1,201 @@
+//! Sector cache in front of the disks, shared by everything above them
+//!
+//! Writes only reach the cache and are written back later: every few seconds
+//! from the idle loop, when a dirty sector gets evicted, or on `sync`.
+use log::error;
+use alloc::vec;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::Mutex;
+use super::{BlockDevice, Error, SECTOR_SIZE};
+
+const CAPACITY: usize = 256; // sectors, 128KiB
+const READ_AHEAD: u64 = 16; // sectors fetched on a sequential miss
+const MAX_TRANSFER: u64 = 64;
+const WRITE_BACK_INTERVAL_MS: u32 = 5000;
+
+struct Buffer {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    used: u64, // clock value of the last access, the smallest gets evicted
+}
+
+struct Device {
+    inner: Arc<dyn BlockDevice>,
+    last_read: Option<u64>,
+}
+
+struct Cache {
+    buffers: BTreeMap<(usize, u64), Buffer>, // by device and sector
+    devices: Vec<Device>,
+    clock: u64,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache { buffers: BTreeMap::new(), devices: Vec::new(), clock: 0 });
+static WRITE_BACK_DUE: AtomicBool = AtomicBool::new(false);
+
+/// A device whose sectors go through the cache.
+pub struct CachedDevice {
+    id: usize,
+    inner: Arc<dyn BlockDevice>,
+}
+
+pub fn init() {
+    crate::interrupts::pit::every(WRITE_BACK_INTERVAL_MS, || WRITE_BACK_DUE.store(true, Ordering::Relaxed));
+}
+
+pub fn cached(device: Arc<dyn BlockDevice>) -> Arc<CachedDevice> {
+    let mut cache = CACHE.lock();
+    cache.devices.push(Device { inner: device.clone(), last_read: None });
+    Arc::new(CachedDevice { id: cache.devices.len() - 1, inner: device })
+}
+
+/// Writes every dirty sector out and flushes the disks.
+pub fn sync() -> Result<(), Error> {
+    let mut cache = CACHE.lock();
+    cache.write_back(None)?;
+    cache.devices.iter().try_for_each(|device| device.inner.flush())
+}
+
+/// Does the periodic write-back if the timer asked for it. Disk access can't
+/// happen in the timer interrupt, so this gets called from the idle loop.
+pub fn write_back_if_due() {
+    if WRITE_BACK_DUE.swap(false, Ordering::Relaxed) {
+        if let Err(error) = CACHE.lock().write_back(None) {
+            error!("Write-back failed: {:?}", error);
+        }
+    }
+}
+
+impl Cache {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    // makes room for one more buffer
+    fn evict(&mut self) -> Result<(), Error> {
+        if self.buffers.len() < CAPACITY {
+            return Ok(());
+        }
+        let (&key, buffer) = self.buffers.iter().min_by_key(|(_, buffer)| buffer.used).unwrap();
+        if buffer.dirty {
+            self.devices[key.0].inner.write_sectors(key.1, &buffer.data[..])?;
+        }
+        self.buffers.remove(&key);
+        Ok(())
+    }
+
+    fn insert(&mut self, key: (usize, u64), data: &[u8], dirty: bool) -> Result<(), Error> {
+        let used = self.tick();
+        if let Some(buffer) = self.buffers.get_mut(&key) {
+            buffer.data.copy_from_slice(data);
+            buffer.dirty |= dirty;
+            buffer.used = used;
+            return Ok(());
+        }
+        self.evict()?;
+        let buffer = Buffer { data: Box::new(data.try_into().unwrap()), dirty, used };
+        self.buffers.insert(key, buffer);
+        Ok(())
+    }
+
+    // reads `count` sectors from the disk, keeping cached ones since they may be newer
+    fn fill(&mut self, id: usize, start: u64, count: u64) -> Result<(), Error> {
+        let mut data = vec![0; count as usize * SECTOR_SIZE];
+        self.devices[id].inner.read_sectors(start, &mut data)?;
+        for (index, sector) in data.chunks(SECTOR_SIZE).enumerate() {
+            let key = (id, start + index as u64);
+            if !self.buffers.contains_key(&key) {
+                self.insert(key, sector, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    // writes dirty sectors of one device or all of them, in runs of neighbours
+    fn write_back(&mut self, only: Option<usize>) -> Result<(), Error> {
+        let dirty: Vec<(usize, u64)> = self.buffers.iter()
+            .filter(|(key, buffer)| buffer.dirty && only.map_or(true, |id| key.0 == id))
+            .map(|(&key, _)| key)
+            .collect();
+
+        let mut index = 0;
+        while index < dirty.len() {
+            let (id, start) = dirty[index];
+            let mut run = 1;
+            while index + run < dirty.len() && run < MAX_TRANSFER as usize && dirty[index + run] == (id, start + run as u64) {
+                run += 1;
+            }
+
+            let mut data = Vec::with_capacity(run * SECTOR_SIZE);
+            for &key in &dirty[index..index + run] {
+                data.extend_from_slice(&self.buffers[&key].data[..]);
+            }
+            self.devices[id].inner.write_sectors(start, &data)?;
+            for key in &dirty[index..index + run] {
+                self.buffers.get_mut(key).unwrap().dirty = false;
+            }
+            index += run;
+        }
+        Ok(())
+    }
+}
+
+impl CachedDevice {
+    fn check_range(&self, start: u64, len: usize) -> Result<(), Error> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if len % SECTOR_SIZE != 0 || start.checked_add(count).map_or(true, |end| end > self.inner.sector_count()) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn read_sectors(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.check_range(start, buffer.len())?;
+        let mut cache = CACHE.lock();
+        let count = (buffer.len() / SECTOR_SIZE) as u64;
+
+        for (index, out) in buffer.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = start + index as u64;
+            let key = (self.id, sector);
+            if !cache.buffers.contains_key(&key) {
+                // the rest of this request, and more when it continues the last one
+                let sequential = cache.devices[self.id].last_read.is_some_and(|last| last + 1 == start);
+                let wanted = (count - index as u64).max(if sequential { READ_AHEAD } else { 1 });
+                let available = self.inner.sector_count() - sector;
+                cache.fill(self.id, sector, wanted.min(available).min(MAX_TRANSFER))?;
+            }
+            let used = cache.tick();
+            // filling can evict, but never what it just read
+            let buffer = cache.buffers.get_mut(&key).ok_or(Error::OutOfRange)?;
+            buffer.used = used;
+            out.copy_from_slice(&buffer.data[..]);
+        }
+        cache.devices[self.id].last_read = Some(start + count - 1);
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buffer: &[u8]) -> Result<(), Error> {
+        self.check_range(start, buffer.len())?;
+        let mut cache = CACHE.lock();
+        for (index, data) in buffer.chunks(SECTOR_SIZE).enumerate() {
+            cache.insert((self.id, start + index as u64), data, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        CACHE.lock().write_back(Some(self.id))?;
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,108 @@
+//! Block devices: disks and anything else addressed in fixed size sectors
+use spin::Mutex;
+use alloc::vec;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use crate::fs;
+
+pub mod ata;
+pub mod cache;
+pub mod partition;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    OutOfRange,
+    Timeout,
+    Device { status: u8, error: u8 }, // what the controller reported
+}
+
+/// A device read and written in whole sectors. Buffers are a multiple of `SECTOR_SIZE` long.
+pub trait BlockDevice: Send + Sync {
+    fn sector_count(&self) -> u64;
+
+    fn read_sectors(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    fn write_sectors(&self, start: u64, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Makes sure everything written has reached the disk.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+pub fn init() {
+    cache::init();
+    ata::init();
+    for (name, device) in devices() {
+        partition::scan(&name, &device);
+    }
+}
+
+/// Adds a device by name, it also shows up as a file in /dev.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().push((String::from(name), device.clone()));
+    fs::devfs::register(name, Arc::new(DeviceFile(device)));
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|(device, _)| device == name).map(|(_, device)| device.clone())
+}
+
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock().clone()
+}
+
+// byte offsets on top of sectors, reading or writing a whole sector at a time
+struct DeviceFile(Arc<dyn BlockDevice>);
+
+impl DeviceFile {
+    fn size(&self) -> usize {
+        self.0.sector_count() as usize * SECTOR_SIZE
+    }
+}
+
+impl fs::Inode for DeviceFile {
+    fn metadata(&self) -> fs::Metadata {
+        fs::Metadata { kind: fs::Kind::Device, size: self.size() }
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> fs::Result<usize> {
+        let len = buffer.len().min(self.size().saturating_sub(offset));
+        let mut sector = vec![0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let within = position % SECTOR_SIZE;
+            let chunk = (SECTOR_SIZE - within).min(len - done);
+            self.0.read_sectors((position / SECTOR_SIZE) as u64, &mut sector).map_err(|_| fs::Error::Io)?;
+            buffer[done..done + chunk].copy_from_slice(&sector[within..within + chunk]);
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buffer: &[u8]) -> fs::Result<usize> {
+        if offset.checked_add(buffer.len()).map_or(true, |end| end > self.size()) {
+            return Err(fs::Error::NoSpace);
+        }
+        let mut sector = vec![0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done;
+            let (index, within) = ((position / SECTOR_SIZE) as u64, position % SECTOR_SIZE);
+            let chunk = (SECTOR_SIZE - within).min(buffer.len() - done);
+            if chunk < SECTOR_SIZE {
+                self.0.read_sectors(index, &mut sector).map_err(|_| fs::Error::Io)?;
+            }
+            sector[within..within + chunk].copy_from_slice(&buffer[done..done + chunk]);
+            self.0.write_sectors(index, &sector).map_err(|_| fs::Error::Io)?;
+            done += chunk;
+        }
+        Ok(done)
+    }
+}
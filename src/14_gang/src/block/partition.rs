@@ -0,0 +1,204 @@
+//! MBR and GPT partition tables, each partition becomes a device of its own
+use log::warn;
+use alloc::vec;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::util::crc32;
+use super::{BlockDevice, Error, SECTOR_SIZE};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const PROTECTIVE_MBR: u8 = 0xEE;
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MAX_LOGICAL: usize = 64; // guards against loops in the EBR chain
+
+/// A range of sectors on another device.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    sectors: u64,
+}
+
+impl Partition {
+    fn check_range(&self, start: u64, len: usize) -> Result<u64, Error> {
+        let count = (len / SECTOR_SIZE) as u64;
+        match start.checked_add(count) {
+            Some(end) if end <= self.sectors => Ok(self.start + start),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let start = self.check_range(start, buffer.len())?;
+        self.device.read_sectors(start, buffer)
+    }
+
+    fn write_sectors(&self, start: u64, buffer: &[u8]) -> Result<(), Error> {
+        let start = self.check_range(start, buffer.len())?;
+        self.device.write_sectors(start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.device.flush()
+    }
+}
+
+/// Registers every partition on `device` as `name` followed by its number,
+/// numbered like Linux does: primaries 1 to 4, logical partitions from 5.
+pub fn scan(name: &str, device: &Arc<dyn BlockDevice>) {
+    let partitions = match read_table(device) {
+        Ok(partitions) => partitions,
+        Err(error) => {
+            warn!("{}: couldn't read partition table: {:?}", name, error);
+            return;
+        },
+    };
+    for (number, start, sectors) in partitions {
+        if start.checked_add(sectors).map_or(true, |end| end > device.sector_count()) || sectors == 0 {
+            warn!("{}{}: partition lies outside the disk", name, number);
+            continue;
+        }
+        let partition = Partition { device: device.clone(), start, sectors };
+        super::register(&format!("{}{}", name, number), Arc::new(partition));
+    }
+}
+
+// (number, first sector, sector count) of each partition
+fn read_table(device: &Arc<dyn BlockDevice>) -> Result<Vec<(usize, u64, u64)>, Error> {
+    let mut mbr = vec![0; SECTOR_SIZE];
+    device.read_sectors(0, &mut mbr)?;
+    // a FAT volume without partitions has the signature too, but boot code where the entries go
+    if mbr[510..512] != MBR_SIGNATURE || !valid_status(&mbr) {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|&(kind, _, _)| kind == PROTECTIVE_MBR) {
+        return gpt(device);
+    }
+
+    let mut partitions = Vec::new();
+    let mut logical = 5;
+    for (index, &(kind, start, sectors)) in entries.iter().enumerate() {
+        if kind == 0 {
+            continue;
+        }
+        if EXTENDED.contains(&kind) {
+            for (start, sectors) in extended(device, start)? {
+                partitions.push((logical, start, sectors));
+                logical += 1;
+            }
+        } else {
+            partitions.push((index + 1, start, sectors));
+        }
+    }
+    Ok(partitions)
+}
+
+// every entry's status byte says bootable or not, anything else isn't a partition table
+fn valid_status(sector: &[u8]) -> bool {
+    (0..4).all(|index| matches!(sector[446 + index * 16], 0x00 | 0x80))
+}
+
+// (type, first sector, sector count) of the four entries
+fn mbr_entries(sector: &[u8]) -> [(u8, u64, u64); 4] {
+    core::array::from_fn(|index| {
+        let entry = &sector[446 + index * 16..446 + (index + 1) * 16];
+        (entry[4], u32_at(entry, 8) as u64, u32_at(entry, 12) as u64)
+    })
+}
+
+// Logical partitions are a linked list of EBRs. The first entry of each one is
+// relative to that EBR, the link to the next is relative to the extended partition.
+fn extended(device: &Arc<dyn BlockDevice>, base: u64) -> Result<Vec<(u64, u64)>, Error> {
+    let mut partitions = Vec::new();
+    let mut ebr = base;
+    let mut sector = vec![0; SECTOR_SIZE];
+
+    for _ in 0..MAX_LOGICAL {
+        device.read_sectors(ebr, &mut sector)?;
+        if sector[510..512] != MBR_SIGNATURE || !valid_status(&sector) {
+            break;
+        }
+        let [(kind, start, sectors), (next_kind, next, _), ..] = mbr_entries(&sector);
+        if kind != 0 {
+            partitions.push((ebr + start, sectors));
+        }
+        if next_kind == 0 || next == 0 {
+            break;
+        }
+        ebr = base + next;
+    }
+    Ok(partitions)
+}
+
+// The primary header is at LBA 1 and a backup at the last sector, each
+// with a CRC over itself and one over the partition entries.
+fn gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<(usize, u64, u64)>, Error> {
+    let Some(last) = device.sector_count().checked_sub(1) else {
+        return Ok(Vec::new());
+    };
+    for header_lba in [1, last] {
+        if let Some(partitions) = gpt_at(device, header_lba)? {
+            return Ok(partitions);
+        }
+        warn!("GPT header at sector {} is invalid", header_lba);
+    }
+    Ok(Vec::new())
+}
+
+fn gpt_at(device: &Arc<dyn BlockDevice>, lba: u64) -> Result<Option<Vec<(usize, u64, u64)>>, Error> {
+    let mut header = vec![0; SECTOR_SIZE];
+    device.read_sectors(lba, &mut header)?;
+
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || !(92..=SECTOR_SIZE).contains(&header_size) {
+        return Ok(None);
+    }
+    let expected = u32_at(&header, 16);
+    header[16..20].fill(0); // the crc is computed with its own field zeroed
+    if crc32(&header[..header_size]) != expected {
+        return Ok(None);
+    }
+
+    let entries_lba = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if !entry_size.is_power_of_two() || !(128..=SECTOR_SIZE).contains(&entry_size) || entry_count > 1024 {
+        return Ok(None);
+    }
+    let entries_len = entry_count * entry_size;
+    let mut entries = vec![0; entries_len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+    // a bad sector under the entries shouldn't hide the backup copy
+    if device.read_sectors(entries_lba, &mut entries).is_err() {
+        return Ok(None);
+    }
+    if crc32(&entries[..entries_len]) != u32_at(&header, 88) {
+        return Ok(None);
+    }
+
+    let partitions = entries[..entries_len].chunks(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| entry[0..16].iter().any(|&byte| byte != 0)) // unused entries have no type
+        .filter_map(|(index, entry)| {
+            let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+            Some((index + 1, first, last.checked_add(1)?.saturating_sub(first)))
+        })
+        .collect();
+    Ok(Some(partitions))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,89 @@
+//! Per-CPU data, reached through the GS base
+use alloc::vec;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use alloc::boxed::Box;
+use crate::util::wrmsr;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::interrupts::gdt::{Gdt, Tss, DOUBLE_FAULT_STACK_SIZE, TSS_RSP0_OFFSET};
+
+const GS_BASE_MSR: u32 = 0xC000_0101;
+const KERNEL_GS_BASE_MSR: u32 = 0xC000_0102;
+
+// for assembly, as in gs:[KERNEL_STACK_OFFSET]
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub const USER_STACK_OFFSET: usize = offset_of!(PerCpu, user_stack);
+pub const RSP0_OFFSET: usize = offset_of!(PerCpu, tss) + TSS_RSP0_OFFSET;
+
+// The GS base points here while in the kernel. In ring 3 it's the user's (0) and
+// this sits in KERNEL_GS_BASE, so every entry from and exit to ring 3 does swapgs.
+#[repr(C)]
+pub struct PerCpu {
+    this: *const PerCpu, // gs:0, so finding the structure is a single load
+    pub id: usize,
+    pub process: AtomicUsize, // pid of what's running in ring 3, 0 for nothing
+    interrupt_depth: AtomicUsize,
+    kernel_stack: u64, // where syscalls run, same as rsp0 in the tss
+    user_stack: u64, // scratch space for the syscall entry
+    tss: UnsafeCell<Tss>,
+    gdt: Gdt,
+}
+
+impl PerCpu {
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.load(Ordering::Relaxed) > 0
+    }
+}
+
+/// Sets up the calling CPU's data area along with its own GDT, TSS and double fault stack.
+pub fn init(id: usize) {
+    let stack = vec![0u8; DOUBLE_FAULT_STACK_SIZE].leak();
+    let cpu = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        id,
+        process: AtomicUsize::new(0),
+        interrupt_depth: AtomicUsize::new(0),
+        kernel_stack: 0,
+        user_stack: 0,
+        tss: UnsafeCell::new(Tss::with_double_fault_stack(stack.as_ptr() as u64)),
+        gdt: Gdt::new(),
+    }));
+    cpu.this = cpu;
+    cpu.gdt = Gdt::with_tss(cpu.tss.get_mut());
+    cpu.gdt.activate();
+
+    wrmsr(GS_BASE_MSR, cpu.this as u64);
+    wrmsr(KERNEL_GS_BASE_MSR, 0);
+}
+
+pub fn current() -> &'static PerCpu {
+    let cpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+        &*cpu
+    }
+}
+
+/// Like `current().id`, but also works before `init`, when only the BSP runs.
+pub fn current_id() -> usize {
+    match crate::util::rdmsr(GS_BASE_MSR) {
+        0 => 0,
+        _ => current().id,
+    }
+}
+
+/// Marks the current CPU as handling an interrupt until the guard is dropped.
+pub fn enter_interrupt() -> InterruptGuard {
+    let cpu = current();
+    cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    InterruptGuard(cpu)
+}
+
+pub struct InterruptGuard(&'static PerCpu);
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        self.0.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,230 @@
+//! Loader for static ELF64 executables
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use crate::usermode::{USER_START, USER_END};
+use crate::memory::{self, paging, FRAME_SIZE};
+use crate::memory::paging::{MapError, NO_EXECUTE, USER, WRITABLE};
+
+const USER_STACK_TOP: usize = USER_END - FRAME_SIZE; // leave a guard page below the hole
+const USER_STACK_SIZE: usize = 16 * FRAME_SIZE;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum Error {
+    Truncated,
+    NotElf64,
+    NotExecutable,
+    BadSegment,
+    OutOfMemory,
+    ArgumentsTooLong,
+}
+
+impl From<MapError> for Error {
+    fn from(_: MapError) -> Self {
+        Error::OutOfMemory
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Header {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// A program mapped into its own address space, ready to run.
+pub struct Program {
+    pub p4: usize,
+    pub entry: u64,
+    phdr: Option<u64>, // where the program headers ended up in memory
+    phnum: u16,
+}
+
+fn read<T: Copy>(image: &[u8], offset: u64) -> Result<T, Error> {
+    let offset = offset as usize;
+    let end = offset.checked_add(size_of::<T>()).ok_or(Error::Truncated)?;
+    if end > image.len() {
+        return Err(Error::Truncated);
+    }
+    Ok(unsafe { read_unaligned(image[offset..].as_ptr() as *const T) })
+}
+
+/// Copies `bytes` to `address` in the address space `p4`, which doesn't have to be the active one.
+fn copy_to(p4: usize, address: usize, bytes: &[u8]) {
+    let mut written = 0;
+    while written < bytes.len() {
+        let target = address + written;
+        let physical = paging::translate(p4, target).expect("Copying to unmapped user memory");
+        let chunk = (FRAME_SIZE - target % FRAME_SIZE).min(bytes.len() - written);
+        unsafe { core::ptr::copy_nonoverlapping(bytes[written..].as_ptr(), physical as *mut u8, chunk) };
+        written += chunk;
+    }
+}
+
+// pages shared by two segments get the permissions of both
+fn map_user_pages(p4: usize, start: usize, end: usize, flags: u64) -> Result<(), Error> {
+    for page in (start & !(FRAME_SIZE - 1)..end).step_by(FRAME_SIZE) {
+        match paging::flags(p4, page) {
+            Some(existing) => {
+                let merged = (existing | flags) & !NO_EXECUTE | (existing & flags & NO_EXECUTE);
+                paging::set_flags(p4, page, merged)?;
+            },
+            None => {
+                let frame = memory::allocate_zeroed_frame().ok_or(Error::OutOfMemory)?;
+                paging::map_to(p4, page, frame, flags)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+pub fn load(image: &[u8]) -> Result<Program, Error> {
+    let header: Header = read(image, 0)?;
+    if &header.ident[..4] != b"\x7fELF" || header.ident[4] != 2 || header.ident[5] != 1 || header.machine != 0x3E {
+        return Err(Error::NotElf64);
+    }
+    if header.kind != 2 {
+        return Err(Error::NotExecutable); // static executables only, no relocating
+    }
+
+    // anything else would have the headers overlap or all be the same one
+    if header.phnum > 0 && header.phentsize as usize != size_of::<ProgramHeader>() {
+        return Err(Error::NotElf64);
+    }
+    let program_headers = (0..header.phnum as u64)
+        .map(|i| read::<ProgramHeader>(image, header.phoff.checked_add(i * header.phentsize as u64).ok_or(Error::Truncated)?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let p4 = paging::new_address_space()?;
+    match load_segments(image, &header, &program_headers, p4) {
+        Ok(phdr) => Ok(Program { p4, entry: header.entry, phdr, phnum: header.phnum }),
+        Err(error) => {
+            paging::free_address_space(p4);
+            Err(error)
+        },
+    }
+}
+
+// returns where the program headers are mapped, if they are
+fn load_segments(image: &[u8], header: &Header, program_headers: &[ProgramHeader], p4: usize) -> Result<Option<u64>, Error> {
+    let mut phdr = None;
+
+    for segment in program_headers {
+        if segment.kind == PT_PHDR {
+            phdr = Some(segment.vaddr);
+        }
+        if segment.kind != PT_LOAD || segment.memsz == 0 {
+            continue;
+        }
+
+        let start = segment.vaddr as usize;
+        let end = start.checked_add(segment.memsz as usize).ok_or(Error::BadSegment)?;
+        let file_end = segment.offset.checked_add(segment.filesz).ok_or(Error::BadSegment)? as usize;
+        if start < USER_START || end > USER_END || segment.filesz > segment.memsz || file_end > image.len() {
+            return Err(Error::BadSegment);
+        }
+
+        let mut flags = USER;
+        if segment.flags & PF_W != 0 {
+            flags |= WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= NO_EXECUTE;
+        }
+        map_user_pages(p4, start, end, flags)?;
+
+        // the rest up to memsz is .bss, and the frames came zeroed
+        copy_to(p4, start, &image[segment.offset as usize..file_end]);
+
+        let headers_in_segment = segment.offset..segment.offset + segment.filesz;
+        if phdr.is_none() && headers_in_segment.contains(&header.phoff) {
+            phdr = Some(segment.vaddr + header.phoff - segment.offset);
+        }
+    }
+
+    Ok(phdr)
+}
+
+impl Program {
+    /// Maps the user stack and lays out argc, argv, envp and auxv on it like
+    /// the System V ABI wants them. Returns the initial stack pointer.
+    pub fn setup_stack(&self, argv: &[&str], envp: &[&str]) -> Result<u64, Error> {
+        // everything has to fit before anything is copied, copy_to can't fail gracefully
+        let strings: usize = argv.iter().chain(envp).map(|string| string.len() + 1).sum();
+        let word_count = 1 + argv.len() + 1 + envp.len() + 1 + 12; // argc, both lists and their nulls, auxv
+        if strings + word_count * size_of::<u64>() + 0xF > USER_STACK_SIZE {
+            return Err(Error::ArgumentsTooLong);
+        }
+
+        let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+        map_user_pages(self.p4, stack_bottom, USER_STACK_TOP, USER | WRITABLE | NO_EXECUTE)?;
+
+        let mut top = USER_STACK_TOP;
+        let mut push_string = |string: &str| {
+            top -= string.len() + 1;
+            copy_to(self.p4, top, string.as_bytes());
+            copy_to(self.p4, top + string.len(), &[0]);
+            top as u64
+        };
+        let argv_pointers: Vec<u64> = argv.iter().map(|arg| push_string(arg)).collect();
+        let envp_pointers: Vec<u64> = envp.iter().map(|env| push_string(env)).collect();
+
+        let mut auxv = Vec::new();
+        if let Some(phdr) = self.phdr {
+            auxv.extend([AT_PHDR, phdr, AT_PHENT, size_of::<ProgramHeader>() as u64, AT_PHNUM, self.phnum as u64]);
+        }
+        auxv.extend([AT_PAGESZ, FRAME_SIZE as u64, AT_ENTRY, self.entry, AT_NULL, 0]);
+
+        let mut words = Vec::new();
+        words.push(argv.len() as u64);
+        words.extend(argv_pointers);
+        words.push(0);
+        words.extend(envp_pointers);
+        words.push(0);
+        words.extend(auxv);
+
+        let size = words.len() * size_of::<u64>();
+        let rsp = (top - size) & !0xF; // argc has to be 16 byte aligned
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        copy_to(self.p4, rsp, &bytes);
+        Ok(rsp as u64)
+    }
+}
@@ -0,0 +1,123 @@
+//! Devices as files under /dev
+//!
+//! Drivers register a node under a name, next to a few built in devices.
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use super::{DirEntry, Error, FileSystem, Inode, Kind, Metadata, Result};
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn Inode>)>> = Mutex::new(Vec::new());
+
+pub struct DevFs;
+
+struct Root;
+
+impl FileSystem for DevFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+/// Makes a device show up as /dev/`name`. Console, null and zero are always there.
+pub fn register(name: &str, device: Arc<dyn Inode>) {
+    let mut devices = DEVICES.lock();
+    devices.retain(|(existing, _)| existing != name);
+    devices.push((String::from(name), device));
+}
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: Kind::Directory, size: 0 }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        builtin(name)
+            .or_else(|| DEVICES.lock().iter().find(|(device, _)| device == name).map(|(_, node)| node.clone()))
+            .ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let mut entries: Vec<DirEntry> = ["console", "null", "zero"].iter()
+            .map(|&name| DirEntry { name: String::from(name), kind: Kind::Device })
+            .collect();
+        for (name, _) in DEVICES.lock().iter() {
+            if builtin(name).is_none() {
+                entries.push(DirEntry { name: name.clone(), kind: Kind::Device });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn builtin(name: &str) -> Option<Arc<dyn Inode>> {
+    match name {
+        "console" => Some(Arc::new(Console)),
+        "null" => Some(Arc::new(Null)),
+        "zero" => Some(Arc::new(Zero)),
+        _ => None,
+    }
+}
+
+fn device() -> Metadata {
+    Metadata { kind: Kind::Device, size: 0 }
+}
+
+/// The screen and COM1. Reads return whatever has arrived on the serial port, without waiting.
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        device()
+    }
+
+    fn read_at(&self, _: usize, buffer: &mut [u8]) -> Result<usize> {
+        let mut read = 0;
+        for slot in buffer.iter_mut() {
+            match crate::serial::read_byte() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _: usize, buffer: &[u8]) -> Result<usize> {
+        print!("{}", String::from_utf8_lossy(buffer));
+        Ok(buffer.len())
+    }
+}
+
+struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> Metadata {
+        device()
+    }
+
+    fn read_at(&self, _: usize, _: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _: usize, buffer: &[u8]) -> Result<usize> {
+        Ok(buffer.len())
+    }
+}
+
+struct Zero;
+
+impl Inode for Zero {
+    fn metadata(&self) -> Metadata {
+        device()
+    }
+
+    fn read_at(&self, _: usize, buffer: &mut [u8]) -> Result<usize> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, _: usize, buffer: &[u8]) -> Result<usize> {
+        Ok(buffer.len())
+    }
+}
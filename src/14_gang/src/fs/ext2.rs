@@ -0,0 +1,294 @@
+//! Read-only ext2
+//!
+//! Inodes are read from disk when they're looked up and never change after,
+//! since nothing here writes.
+use log::warn;
+use alloc::vec;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use super::{DirEntry, Error, FileSystem, Inode, Kind, Metadata, Result};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+const DIRECT_BLOCKS: usize = 12;
+
+// incompatible features we can read past, anything else is refused
+const FEATURE_FILETYPE: u32 = 0x2;
+
+// inode modes
+const TYPE_MASK: u16 = 0xF000;
+const TYPE_DIRECTORY: u16 = 0x4000;
+const TYPE_FILE: u16 = 0x8000;
+const TYPE_SYMLINK: u16 = 0xA000;
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    inodes_per_group: u32,
+    inode_size: usize,
+    inode_tables: Vec<u32>, // first block of each group's inode table
+    has_file_type: bool,
+}
+
+struct Node {
+    volume: Arc<Volume>,
+    mode: u16,
+    size: u64,
+    sectors: u32, // i_blocks, in 512 byte units
+    file_acl: u32,
+    blocks: [u32; 15],
+}
+
+impl Ext2Fs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2Fs> {
+        let mut superblock = vec![0; 1024];
+        let first = SUPERBLOCK_OFFSET / SECTOR_SIZE as u64;
+        device.read_sectors(first, &mut superblock).map_err(|_| Error::Io)?;
+
+        if u16_at(&superblock, 56) != MAGIC {
+            return Err(Error::InvalidArgument);
+        }
+        let incompatible = u32_at(&superblock, 96);
+        if incompatible & !FEATURE_FILETYPE != 0 {
+            warn!("Unsupported features {:#x}", incompatible);
+            return Err(Error::InvalidArgument);
+        }
+
+        let inodes_count = u32_at(&superblock, 0);
+        let blocks_count = u32_at(&superblock, 4);
+        let first_data_block = u32_at(&superblock, 20);
+        let log_block_size = u32_at(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(Error::InvalidArgument); // past 64 KiB, and the shift could overflow
+        }
+        let block_size = 1024 << log_block_size;
+        let blocks_per_group = u32_at(&superblock, 32);
+        let inodes_per_group = u32_at(&superblock, 40);
+        let inode_size = match u32_at(&superblock, 76) {
+            0 => 128, // revision 0 has fixed size inodes
+            _ => u16_at(&superblock, 88) as usize,
+        };
+        if blocks_per_group == 0 || inodes_per_group == 0 || inode_size < 128 || inode_size > block_size || !inode_size.is_power_of_two() {
+            return Err(Error::InvalidArgument);
+        }
+
+        let group_count = blocks_count.saturating_sub(first_data_block).div_ceil(blocks_per_group)
+            .max(inodes_count.div_ceil(inodes_per_group)) as usize;
+        let mut volume = Volume {
+            device, block_size, inodes_per_group, inode_size,
+            inode_tables: Vec::new(),
+            has_file_type: incompatible & FEATURE_FILETYPE != 0,
+        };
+
+        // the descriptor table starts in the block after the superblock
+        let mut descriptors = vec![0; (group_count * 32).div_ceil(block_size) * block_size];
+        volume.read_blocks(first_data_block + 1, &mut descriptors)?;
+        volume.inode_tables = descriptors.chunks(32).take(group_count).map(|descriptor| u32_at(descriptor, 8)).collect();
+
+        let volume = Arc::new(volume);
+        if volume.inode(ROOT_INODE)?.mode & TYPE_MASK != TYPE_DIRECTORY {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(Ext2Fs { volume })
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(self.volume.inode(ROOT_INODE).expect("ext2 root inode went away"))
+    }
+}
+
+impl Volume {
+    fn read_blocks(&self, block: u32, buffer: &mut [u8]) -> Result<()> {
+        let sector = block as u64 * (self.block_size / SECTOR_SIZE) as u64;
+        self.device.read_sectors(sector, buffer).map_err(|_| Error::Io)
+    }
+
+    fn inode(self: &Arc<Self>, number: u32) -> Result<Node> {
+        let index = number.checked_sub(1).ok_or(Error::NotFound)?;
+        let table = *self.inode_tables.get((index / self.inodes_per_group) as usize).ok_or(Error::NotFound)?;
+        let offset = (index % self.inodes_per_group) as usize * self.inode_size;
+
+        let mut block = vec![0; self.block_size];
+        let block_number = table.checked_add((offset / self.block_size) as u32).ok_or(Error::Io)?;
+        self.read_blocks(block_number, &mut block)?;
+        let inode = &block[offset % self.block_size..];
+
+        let mode = u16_at(inode, 0);
+        // the high half of the size is only there for regular files
+        let size_high = if mode & TYPE_MASK == TYPE_FILE { u32_at(inode, 108) as u64 } else { 0 };
+        Ok(Node {
+            volume: self.clone(),
+            mode,
+            size: size_high << 32 | u32_at(inode, 4) as u64,
+            sectors: u32_at(inode, 28),
+            file_acl: u32_at(inode, 104),
+            blocks: core::array::from_fn(|index| u32_at(inode, 40 + index * 4)),
+        })
+    }
+}
+
+impl Node {
+    fn kind(&self) -> Kind {
+        match self.mode & TYPE_MASK {
+            TYPE_DIRECTORY => Kind::Directory,
+            TYPE_SYMLINK => Kind::Symlink,
+            TYPE_FILE => Kind::File,
+            _ => Kind::Device,
+        }
+    }
+
+    // the block holding logical block `index` of the file, 0 for a hole
+    fn block(&self, index: usize) -> Result<u32> {
+        let per_block = self.volume.block_size / 4;
+        if index < DIRECT_BLOCKS {
+            return Ok(self.blocks[index]);
+        }
+
+        // single, double and triple indirect blocks cover ever larger ranges after the direct ones
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        for level in 0..3 {
+            if index < span {
+                let mut block = self.blocks[DIRECT_BLOCKS + level];
+                for depth in (0..=level).rev() {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    let entry = index / per_block.pow(depth as u32) % per_block;
+                    block = self.pointer(block, entry)?;
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per_block;
+        }
+        Err(Error::Io)
+    }
+
+    fn pointer(&self, block: u32, entry: usize) -> Result<u32> {
+        let mut pointers = vec![0; self.volume.block_size];
+        self.volume.read_blocks(block, &mut pointers)?;
+        Ok(u32_at(&pointers, entry * 4))
+    }
+
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize> {
+        let block_size = self.volume.block_size;
+        let len = buffer.len().min((self.size as usize).saturating_sub(offset));
+        let mut data = vec![0; block_size];
+        let mut done = 0;
+        while done < len {
+            let at = offset + done;
+            let within = at % block_size;
+            let chunk = (block_size - within).min(len - done);
+            match self.block(at / block_size)? {
+                0 => data.fill(0),
+                block => self.volume.read_blocks(block, &mut data)?,
+            }
+            buffer[done..done + chunk].copy_from_slice(&data[within..within + chunk]);
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    // (inode, name, kind if the directory records it) for every entry
+    fn entries(&self) -> Result<Vec<(u32, String, Option<Kind>)>> {
+        if self.kind() != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let mut contents = vec![0; self.size as usize];
+        self.read(0, &mut contents)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= contents.len() {
+            let entry = &contents[offset..];
+            let (inode, record_len) = (u32_at(entry, 0), u16_at(entry, 4) as usize);
+            if record_len < 8 {
+                return Err(Error::Io);
+            }
+            let name_len = if self.volume.has_file_type { entry[6] as usize } else { u16_at(entry, 6) as usize };
+            if inode != 0 && 8 + name_len <= record_len.min(entry.len()) {
+                let name = String::from_utf8_lossy(&entry[8..8 + name_len]).into_owned();
+                let kind = match (self.volume.has_file_type, entry[7]) {
+                    (false, _) => None,
+                    (true, 1) => Some(Kind::File),
+                    (true, 2) => Some(Kind::Directory),
+                    (true, 7) => Some(Kind::Symlink),
+                    (true, _) => Some(Kind::Device),
+                };
+                if name != "." && name != ".." {
+                    entries.push((inode, name, kind));
+                }
+            }
+            offset += record_len;
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: self.kind(), size: self.size as usize }
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize> {
+        match self.kind() {
+            Kind::Directory => Err(Error::IsADirectory),
+            _ => self.read(offset, buffer),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let (inode, _, _) = self.entries()?.into_iter()
+            .find(|(_, entry, _)| entry == name)
+            .ok_or(Error::NotFound)?;
+        Ok(Arc::new(self.volume.inode(inode)?))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        self.entries()?.into_iter()
+            .map(|(inode, name, kind)| {
+                let kind = match kind {
+                    Some(kind) => kind,
+                    None => self.volume.inode(inode)?.kind(),
+                };
+                Ok(DirEntry { name, kind })
+            })
+            .collect()
+    }
+
+    // Short targets live right in the block pointers, as long as no data
+    // blocks are in use. An extended attribute block counts towards i_blocks.
+    fn read_link(&self) -> Result<String> {
+        if self.kind() != Kind::Symlink {
+            return Err(Error::InvalidArgument);
+        }
+        let attribute_sectors = if self.file_acl != 0 { (self.volume.block_size / SECTOR_SIZE) as u32 } else { 0 };
+        let len = self.size as usize;
+        let mut target = vec![0; len];
+        if self.sectors == attribute_sectors && len <= 60 {
+            let inline: Vec<u8> = self.blocks.iter().flat_map(|block| block.to_le_bytes()).collect();
+            target.copy_from_slice(&inline[..len]);
+        } else {
+            self.read(0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| Error::Io)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,743 @@
+//! FAT12, FAT16 and FAT32 with long file names
+//!
+//! Every operation goes through the volume lock, and reads and writes go
+//! straight to the block device. Timestamps and the FAT32 FSInfo free count
+//! aren't maintained.
+use alloc::vec;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::BTreeMap;
+use crate::sync::Mutex;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use super::{DirEntry, Error, FileSystem, Inode, Kind, Metadata, Result};
+
+const ENTRY_SIZE: usize = 32;
+const DIRECTORY: u8 = 0x10;
+const VOLUME_LABEL: u8 = 0x08;
+const LONG_NAME: u8 = 0x0F; // read only, hidden, system and volume label at once
+const DELETED: u8 = 0xE5;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    sectors_per_cluster: u64,
+    fat_start: u64, // sectors
+    fat_size: u64,
+    fat_count: u64,
+    root_start: u64, // the fixed root directory on FAT12 and FAT16
+    root_sectors: u64,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32, // 0 for the fixed root directory
+    lock: Mutex<()>,
+    nodes: spin::Mutex<BTreeMap<u64, Weak<Node>>>, // by position of the directory entry
+}
+
+struct Node {
+    volume: Arc<Volume>,
+    info: spin::Mutex<Info>,
+}
+
+#[derive(Clone, Copy)]
+struct Info {
+    kind: Kind,
+    cluster: u32, // first cluster, 0 while a file is empty
+    size: u32,
+    entry: Option<u64>, // byte position of the short entry, None for the root
+    removed: bool, // still open after its entry went, the clusters are freed with the last handle
+}
+
+// a parsed directory entry with its long name if it had one
+struct Found {
+    name: String,
+    short: [u8; 11],
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    first_slot: usize, // where its long name entries start
+    slot: usize,
+}
+
+impl FatFs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs> {
+        let mut boot = vec![0; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot).map_err(|_| Error::Io)?;
+
+        let bytes_per_sector = u16_at(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17) as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_size = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            size => size as u64,
+        };
+        if boot[510..512] != [0x55, 0xAA] || bytes_per_sector != SECTOR_SIZE || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0 || fat_count == 0 || fat_size == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(SECTOR_SIZE as u64);
+        let fat_start = reserved;
+        let root_start = fat_start + fat_count * fat_size;
+        let data_start = root_start + root_sectors;
+        if total <= data_start || total > device.sector_count() {
+            return Err(Error::InvalidArgument);
+        }
+        let cluster_count = ((total - data_start) / sectors_per_cluster) as u32;
+
+        // the type follows from the cluster count alone
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let root_cluster = match fat_type {
+            FatType::Fat32 => u32_at(&boot, 44),
+            _ => 0,
+        };
+
+        let volume = Volume {
+            device, fat_type, sectors_per_cluster, fat_start, fat_size, fat_count, root_start, root_sectors,
+            data_start, cluster_count, root_cluster,
+            lock: Mutex::new(()),
+            nodes: spin::Mutex::new(BTreeMap::new()),
+        };
+        Ok(FatFs { volume: Arc::new(volume) })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.fat_type
+    }
+}
+
+impl FileSystem for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        let info = Info { kind: Kind::Directory, cluster: self.volume.root_cluster, size: 0, entry: None, removed: false };
+        Arc::new(Node { volume: self.volume.clone(), info: spin::Mutex::new(info) })
+    }
+
+    fn sync(&self) {
+        self.volume.device.flush().ok();
+    }
+}
+
+impl Volume {
+    fn cluster_size(&self) -> usize {
+        (self.sectors_per_cluster as usize) * SECTOR_SIZE
+    }
+
+    fn cluster_position(&self, cluster: u32) -> u64 {
+        (self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster) * SECTOR_SIZE as u64
+    }
+
+    // reads bytes anywhere on the device, a sector at a time
+    fn read(&self, position: u64, buffer: &mut [u8]) -> Result<()> {
+        let mut sector = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = position + done as u64;
+            let within = (at % SECTOR_SIZE as u64) as usize;
+            let chunk = (SECTOR_SIZE - within).min(buffer.len() - done);
+            self.device.read_sectors(at / SECTOR_SIZE as u64, &mut sector).map_err(|_| Error::Io)?;
+            buffer[done..done + chunk].copy_from_slice(&sector[within..within + chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn write(&self, position: u64, buffer: &[u8]) -> Result<()> {
+        let mut sector = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = position + done as u64;
+            let within = (at % SECTOR_SIZE as u64) as usize;
+            let chunk = (SECTOR_SIZE - within).min(buffer.len() - done);
+            if chunk < SECTOR_SIZE {
+                self.device.read_sectors(at / SECTOR_SIZE as u64, &mut sector).map_err(|_| Error::Io)?;
+            }
+            sector[within..within + chunk].copy_from_slice(&buffer[done..done + chunk]);
+            self.device.write_sectors(at / SECTOR_SIZE as u64, &sector).map_err(|_| Error::Io)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat12 => value >= 0xFF8,
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFF_FFF8,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    // byte offset of a cluster's entry within a FAT
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let position = self.fat_start * SECTOR_SIZE as u64 + self.fat_offset(cluster);
+        let mut bytes = [0; 4];
+        match self.fat_type {
+            FatType::Fat12 => {
+                self.read(position, &mut bytes[..2])?;
+                let value = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+                Ok(if cluster % 2 == 0 { value & 0xFFF } else { value >> 4 })
+            },
+            FatType::Fat16 => {
+                self.read(position, &mut bytes[..2])?;
+                Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+            },
+            FatType::Fat32 => {
+                self.read(position, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes) & 0x0FFF_FFFF)
+            },
+        }
+    }
+
+    // updates every copy of the FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        for fat in 0..self.fat_count {
+            let position = (self.fat_start + fat * self.fat_size) * SECTOR_SIZE as u64 + self.fat_offset(cluster);
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let mut bytes = [0; 2];
+                    self.read(position, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let new = match cluster % 2 {
+                        0 => old & 0xF000 | value as u16 & 0xFFF,
+                        _ => old & 0x000F | (value as u16) << 4,
+                    };
+                    self.write(position, &new.to_le_bytes())?;
+                },
+                FatType::Fat16 => self.write(position, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // the top four bits are reserved and kept as they are
+                    let mut bytes = [0; 4];
+                    self.read(position, &mut bytes)?;
+                    let value = u32::from_le_bytes(bytes) & 0xF000_0000 | value & 0x0FFF_FFFF;
+                    self.write(position, &value.to_le_bytes())?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster >= 2 && !self.is_end_of_chain(cluster) {
+            if cluster >= self.cluster_count + 2 || clusters.len() > self.cluster_count as usize {
+                return Err(Error::Io); // broken or looping chain
+            }
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    // a zeroed cluster, linked after `previous` if there is one
+    fn allocate_cluster(&self, previous: Option<u32>) -> Result<u32> {
+        let cluster = (2..self.cluster_count + 2)
+            .find(|&cluster| self.fat_entry(cluster).map_or(false, |entry| entry == 0))
+            .ok_or(Error::NoSpace)?;
+        self.set_fat_entry(cluster, self.end_of_chain())?;
+        self.write(self.cluster_position(cluster), &vec![0; self.cluster_size()])?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    // byte positions of every entry slot in a directory
+    fn slots(&self, cluster: u32) -> Result<Vec<u64>> {
+        let per_sector = (SECTOR_SIZE / ENTRY_SIZE) as u64;
+        let sectors: Vec<u64> = match cluster {
+            0 => (self.root_start..self.root_start + self.root_sectors).collect(),
+            _ => self.chain(cluster)?.iter()
+                .flat_map(|&cluster| {
+                    let first = self.cluster_position(cluster) / SECTOR_SIZE as u64;
+                    first..first + self.sectors_per_cluster
+                })
+                .collect(),
+        };
+        Ok(sectors.iter()
+            .flat_map(|sector| (0..per_sector).map(move |index| sector * SECTOR_SIZE as u64 + index * ENTRY_SIZE as u64))
+            .collect())
+    }
+
+    fn read_entries(&self, slots: &[u64]) -> Result<Vec<[u8; ENTRY_SIZE]>> {
+        let mut entries = Vec::with_capacity(slots.len());
+        for &slot in slots {
+            let mut entry = [0; ENTRY_SIZE];
+            self.read(slot, &mut entry)?;
+            if entry[0] == 0 {
+                break; // nothing is in use past the first never used entry
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn list(&self, cluster: u32) -> Result<Vec<Found>> {
+        let entries = self.read_entries(&self.slots(cluster)?)?;
+        let mut found = Vec::new();
+        let mut long_name: Vec<(u8, [u16; LONG_NAME_CHARS])> = Vec::new();
+        let mut long_start = 0;
+
+        for (slot, entry) in entries.iter().enumerate() {
+            if entry[0] == DELETED {
+                long_name.clear();
+                continue;
+            }
+            if entry[11] & 0x3F == LONG_NAME {
+                if entry[0] & LAST_LONG_ENTRY != 0 {
+                    long_name.clear();
+                    long_start = slot;
+                }
+                long_name.push((entry[13], long_name_chars(entry)));
+                continue;
+            }
+            if entry[11] & VOLUME_LABEL != 0 {
+                long_name.clear();
+                continue;
+            }
+
+            let short: [u8; 11] = entry[..11].try_into().unwrap();
+            let checksum = short_name_checksum(&short);
+            let name = match long_name.iter().all(|&(sum, _)| sum == checksum) && !long_name.is_empty() {
+                true => {
+                    // stored last part first
+                    let units: Vec<u16> = long_name.iter().rev().flat_map(|(_, chars)| chars.iter().copied())
+                        .take_while(|&unit| unit != 0)
+                        .collect();
+                    String::from_utf16_lossy(&units)
+                },
+                false => {
+                    long_start = slot;
+                    short_name_string(&short)
+                },
+            };
+            long_name.clear();
+            if name == "." || name == ".." {
+                continue;
+            }
+
+            let cluster = (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32;
+            found.push(Found { name, short, attributes: entry[11], cluster, size: u32_at(entry, 28), first_slot: long_start, slot });
+        }
+        Ok(found)
+    }
+
+    fn find(&self, directory: u32, name: &str) -> Result<Found> {
+        self.list(directory)?.into_iter()
+            .find(|found| found.name.eq_ignore_ascii_case(name))
+            .ok_or(Error::NotFound)
+    }
+
+    // finds `count` free slots in a row, growing the directory if it has to
+    fn free_slots(&self, directory: u32, count: usize) -> Result<Vec<u64>> {
+        loop {
+            let slots = self.slots(directory)?;
+            let mut run = 0;
+            for (index, &slot) in slots.iter().enumerate() {
+                let mut first = [0];
+                self.read(slot, &mut first)?;
+                run = if first[0] == 0 || first[0] == DELETED { run + 1 } else { 0 };
+                if run == count {
+                    return Ok(slots[index + 1 - count..=index].to_vec());
+                }
+            }
+            if directory == 0 {
+                return Err(Error::NoSpace); // the fixed root can't grow
+            }
+            let last = *self.chain(directory)?.last().unwrap();
+            self.allocate_cluster(Some(last))?;
+        }
+    }
+
+    fn node(self: &Arc<Self>, info: Info) -> Arc<Node> {
+        let position = info.entry.unwrap();
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&position).and_then(Weak::upgrade) {
+            return node;
+        }
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let node = Arc::new(Node { volume: self.clone(), info: spin::Mutex::new(info) });
+        nodes.insert(position, Arc::downgrade(&node));
+        node
+    }
+
+    fn save(&self, info: &Info) -> Result<()> {
+        let Some(position) = info.entry else {
+            return Ok(());
+        };
+        let mut entry = [0; ENTRY_SIZE];
+        self.read(position, &mut entry)?;
+        entry[20..22].copy_from_slice(&((info.cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(info.cluster as u16).to_le_bytes());
+        if info.kind == Kind::File {
+            entry[28..32].copy_from_slice(&info.size.to_le_bytes());
+        }
+        self.write(position, &entry)
+    }
+}
+
+impl Node {
+    // copies data in or out starting at `offset`, which has to be inside the allocated clusters
+    fn transfer(&self, info: &Info, offset: usize, len: usize, mut f: impl FnMut(u64, usize, usize) -> Result<()>) -> Result<()> {
+        let volume = &self.volume;
+        let cluster_size = volume.cluster_size();
+        let chain = volume.chain(info.cluster)?;
+        let mut done = 0;
+        while done < len {
+            let at = offset + done;
+            let within = at % cluster_size;
+            let chunk = (cluster_size - within).min(len - done);
+            let cluster = *chain.get(at / cluster_size).ok_or(Error::Io)?;
+            f(volume.cluster_position(cluster) + within as u64, done, chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    // grows the chain to hold `size` bytes, new clusters come zeroed
+    fn reserve(&self, info: &mut Info, size: usize) -> Result<()> {
+        let volume = &self.volume;
+        let chain = volume.chain(info.cluster)?;
+        let needed = size.div_ceil(volume.cluster_size());
+        let mut last = chain.last().copied();
+        for _ in chain.len()..needed {
+            let cluster = volume.allocate_cluster(last)?;
+            if last.is_none() {
+                info.cluster = cluster;
+            }
+            last = Some(cluster);
+        }
+        Ok(())
+    }
+
+    fn directory(&self) -> Result<u32> {
+        let info = *self.info.lock();
+        match info.kind {
+            Kind::Directory if info.removed => Err(Error::NotFound),
+            Kind::Directory => Ok(info.cluster),
+            _ => Err(Error::NotADirectory),
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let info = *self.info.lock();
+        if info.removed && info.cluster != 0 {
+            let _lock = self.volume.lock.lock();
+            let _ = self.volume.free_chain(info.cluster);
+        }
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let info = self.info.lock();
+        Metadata { kind: info.kind, size: info.size as usize }
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize> {
+        let _lock = self.volume.lock.lock();
+        let info = *self.info.lock();
+        if info.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        let len = buffer.len().min((info.size as usize).saturating_sub(offset));
+        self.transfer(&info, offset, len, |position, done, chunk| {
+            self.volume.read(position, &mut buffer[done..done + chunk])
+        })?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buffer: &[u8]) -> Result<usize> {
+        let _lock = self.volume.lock.lock();
+        let mut info = *self.info.lock();
+        if info.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        let end = offset.checked_add(buffer.len()).filter(|&end| end <= u32::MAX as usize).ok_or(Error::NoSpace)?;
+        self.reserve(&mut info, end)?;
+
+        // whatever is between the old end and the offset has to read back as zeros
+        let gap = (info.size as usize).min(offset)..offset;
+        self.transfer(&info, gap.start, gap.len(), |position, _, chunk| self.volume.write(position, &vec![0; chunk]))?;
+        self.transfer(&info, offset, buffer.len(), |position, done, chunk| {
+            self.volume.write(position, &buffer[done..done + chunk])
+        })?;
+
+        info.size = info.size.max(end as u32);
+        self.volume.save(&info)?;
+        *self.info.lock() = info;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: usize) -> Result<()> {
+        let _lock = self.volume.lock.lock();
+        let mut info = *self.info.lock();
+        if info.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        if size > u32::MAX as usize {
+            return Err(Error::NoSpace);
+        }
+
+        let volume = &self.volume;
+        if size > info.size as usize {
+            self.reserve(&mut info, size)?;
+            let gap = info.size as usize..size;
+            self.transfer(&info, gap.start, gap.len(), |position, _, chunk| volume.write(position, &vec![0; chunk]))?;
+        } else {
+            let chain = volume.chain(info.cluster)?;
+            let keep = size.div_ceil(volume.cluster_size());
+            if keep == 0 {
+                volume.free_chain(info.cluster)?;
+                info.cluster = 0;
+            } else if keep < chain.len() {
+                volume.free_chain(chain[keep])?;
+                volume.set_fat_entry(chain[keep - 1], volume.end_of_chain())?;
+            }
+        }
+
+        info.size = size as u32;
+        volume.save(&info)?;
+        *self.info.lock() = info;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let _lock = self.volume.lock.lock();
+        let directory = self.directory()?;
+        let found = self.volume.find(directory, name)?;
+        let slots = self.volume.slots(directory)?;
+        let kind = if found.attributes & DIRECTORY != 0 { Kind::Directory } else { Kind::File };
+        let info = Info { kind, cluster: found.cluster, size: found.size, entry: Some(slots[found.slot]), removed: false };
+        Ok(self.volume.node(info))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let _lock = self.volume.lock.lock();
+        let directory = self.directory()?;
+        Ok(self.volume.list(directory)?.into_iter()
+            .map(|found| {
+                let kind = if found.attributes & DIRECTORY != 0 { Kind::Directory } else { Kind::File };
+                DirEntry { name: found.name, kind }
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: Kind) -> Result<Arc<dyn Inode>> {
+        let _lock = self.volume.lock.lock();
+        let volume = &self.volume;
+        let directory = self.directory()?;
+        if name.is_empty() || name.len() > 255 || name.chars().any(|c| "\\/:*?\"<>|".contains(c) || (c as u32) < 0x20) {
+            return Err(Error::InvalidPath);
+        }
+        let existing = volume.list(directory)?;
+        if existing.iter().any(|found| found.name.eq_ignore_ascii_case(name)) {
+            return Err(Error::AlreadyExists);
+        }
+
+        let (short, needs_long_name) = short_name(name, &existing);
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let long_count = if needs_long_name { units.len().div_ceil(LONG_NAME_CHARS) } else { 0 };
+        let slots = volume.free_slots(directory, long_count + 1)?;
+
+        let (attributes, cluster) = match kind {
+            Kind::Directory => {
+                let cluster = volume.allocate_cluster(None)?;
+                let mut dots = [0; 2 * ENTRY_SIZE];
+                dots[..ENTRY_SIZE].copy_from_slice(&short_entry(b".          ", DIRECTORY, cluster));
+                // ".." always says 0 for the root, even on FAT32 where the root has a cluster
+                let parent = if directory == volume.root_cluster { 0 } else { directory };
+                dots[ENTRY_SIZE..].copy_from_slice(&short_entry(b"..         ", DIRECTORY, parent));
+                volume.write(volume.cluster_position(cluster), &dots)?;
+                (DIRECTORY, cluster)
+            },
+            _ => (0, 0),
+        };
+
+        let checksum = short_name_checksum(&short);
+        for (index, &slot) in slots[..long_count].iter().enumerate() {
+            let order = (long_count - index) as u8;
+            let flag = if index == 0 { LAST_LONG_ENTRY } else { 0 };
+            let part = &units[(order as usize - 1) * LONG_NAME_CHARS..];
+            volume.write(slot, &long_entry(order | flag, part, checksum))?;
+        }
+        volume.write(slots[long_count], &short_entry(&short, attributes, cluster))?;
+
+        let kind = if kind == Kind::Directory { Kind::Directory } else { Kind::File };
+        let info = Info { kind, cluster, size: 0, entry: Some(slots[long_count]), removed: false };
+        Ok(volume.node(info))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let open; // dropped after the lock, the last handle going frees clusters
+        let _lock = self.volume.lock.lock();
+        let volume = &self.volume;
+        let directory = self.directory()?;
+        let found = volume.find(directory, name)?;
+        if found.attributes & DIRECTORY != 0 && !volume.list(found.cluster)?.is_empty() {
+            return Err(Error::NotEmpty);
+        }
+
+        let slots = volume.slots(directory)?;
+        for &slot in &slots[found.first_slot..=found.slot] {
+            volume.write(slot, &[DELETED])?;
+        }
+
+        // an open handle must not write to the freed clusters or the slot, which new files may get
+        open = volume.nodes.lock().remove(&slots[found.slot]).and_then(|node| node.upgrade());
+        match &open {
+            Some(node) => {
+                let mut info = node.info.lock();
+                info.entry = None;
+                info.removed = true;
+            },
+            None => volume.free_chain(found.cluster)?,
+        }
+        Ok(())
+    }
+}
+
+// the short name for a new entry, and whether it needs a long name too
+fn short_name(name: &str, existing: &[Found]) -> ([u8; 11], bool) {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c);
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+
+    let fits = base.len() <= 8 && extension.len() <= 3 && base.chars().chain(extension.chars()).all(valid);
+    if fits && name == name.to_ascii_uppercase() {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base.as_bytes());
+        short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+        return (short, false);
+    }
+
+    let clean = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| if valid(c) { c.to_ascii_uppercase() as u8 } else { b'_' })
+            .take(len)
+            .collect()
+    };
+    let base = clean(base, 6);
+    let extension = clean(extension, 3);
+
+    // NAME~1.EXT, NAME~2.EXT and so on, shortening the name as the number grows
+    for number in 1.. {
+        let suffix = alloc::format!("~{}", number);
+        let keep = base.len().min(8 - suffix.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+        short[8..8 + extension.len()].copy_from_slice(&extension);
+        if !existing.iter().any(|found| found.short == short) {
+            return (short, true);
+        }
+    }
+    unreachable!()
+}
+
+fn short_name_string(short: &[u8; 11]) -> String {
+    let base = core::str::from_utf8(&short[..8]).unwrap_or("").trim_end();
+    let extension = core::str::from_utf8(&short[8..]).unwrap_or("").trim_end();
+    let mut name = String::from(base);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(extension);
+    }
+    name
+}
+
+fn short_name_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte))
+}
+
+fn short_entry(short: &[u8; 11], attributes: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attributes;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry
+}
+
+// the name characters sit in three runs: 1..11, 14..26 and 28..32
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+fn long_name_chars(entry: &[u8; ENTRY_SIZE]) -> [u16; LONG_NAME_CHARS] {
+    LONG_NAME_OFFSETS.map(|offset| u16_at(entry, offset))
+}
+
+// a name that ends early is terminated with a 0 and padded with 0xFFFF
+fn long_entry(order: u8, part: &[u16], checksum: u8) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = order;
+    entry[11] = LONG_NAME;
+    entry[13] = checksum;
+    for (index, offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+        let unit = match index.cmp(&part.len()) {
+            core::cmp::Ordering::Less => part[index],
+            core::cmp::Ordering::Equal => 0,
+            core::cmp::Ordering::Greater => 0xFFFF,
+        };
+        entry[*offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    entry
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
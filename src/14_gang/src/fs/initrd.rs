@@ -0,0 +1,85 @@
+//! The boot module archive as a read-only filesystem
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::BTreeMap;
+use super::{DirEntry, Error, FileSystem, Inode, Kind, Metadata, Result};
+use crate::initrd;
+
+pub struct InitrdFs {
+    root: Arc<Node>,
+}
+
+struct Node {
+    data: &'static [u8],
+    children: Option<BTreeMap<String, Arc<Node>>>, // None for files
+}
+
+// builds the tree while it is still uniquely owned, no locking needed
+#[derive(Default)]
+struct Builder {
+    data: &'static [u8],
+    is_file: bool,
+    children: BTreeMap<String, Builder>,
+}
+
+impl Builder {
+    fn build(self) -> Arc<Node> {
+        let children = (!self.is_file).then(|| {
+            self.children.into_iter().map(|(name, child)| (name, child.build())).collect()
+        });
+        Arc::new(Node { data: self.data, children })
+    }
+}
+
+impl InitrdFs {
+    pub fn new() -> Self {
+        let mut root = Builder::default();
+        for entry in initrd::entries() {
+            // parent directories don't have to come first in the archive
+            let node = entry.path.split('/').fold(&mut root, |node, name| {
+                node.children.entry(String::from(name)).or_default()
+            });
+            node.is_file = entry.kind == initrd::Kind::File;
+            node.data = entry.data;
+        }
+        InitrdFs { root: root.build() }
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        match self.children {
+            Some(_) => Metadata { kind: Kind::Directory, size: 0 },
+            None => Metadata { kind: Kind::File, size: self.data.len() },
+        }
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize> {
+        if self.children.is_some() {
+            return Err(Error::IsADirectory);
+        }
+        let data = self.data.get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let children = self.children.as_ref().ok_or(Error::NotADirectory)?;
+        children.get(name).map(|node| node.clone() as Arc<dyn Inode>).ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let children = self.children.as_ref().ok_or(Error::NotADirectory)?;
+        Ok(children.iter()
+            .map(|(name, node)| DirEntry { name: name.clone(), kind: node.metadata().kind })
+            .collect())
+    }
+}
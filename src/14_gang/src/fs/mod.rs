@@ -0,0 +1,396 @@
+//! Virtual filesystem: mounted filesystems, path lookup and open files
+//!
+//! Paths are absolute. A path belongs to the filesystem mounted at its longest
+//! matching prefix, and the rest of it is looked up one name at a time.
+use log::info;
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::any::Any;
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+pub mod tmpfs;
+
+// flags for open
+pub const READ: u32 = 1 << 0;
+pub const WRITE: u32 = 1 << 1;
+pub const CREATE: u32 = 1 << 2;
+pub const TRUNCATE: u32 = 1 << 3;
+pub const APPEND: u32 = 1 << 4;
+
+const MAX_SYMLINKS: usize = 8; // in one lookup, so loops end
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    ReadOnly,
+    InvalidPath,
+    InvalidArgument,
+    CrossDevice,
+    BadFileDescriptor,
+    NoSpace,
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Device,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: Kind,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: Kind,
+}
+
+/// A file, directory or device inside some filesystem. Everything a node
+/// doesn't support has a default that fails.
+pub trait Inode: Any + Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn read_at(&self, _offset: usize, _buffer: &mut [u8]) -> Result<usize> {
+        Err(Error::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: usize, _buffer: &[u8]) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(Error::NotADirectory)
+    }
+
+    /// Where a symbolic link points.
+    fn read_link(&self) -> Result<String> {
+        Err(Error::InvalidArgument)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Err(Error::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _kind: Kind) -> Result<Arc<dyn Inode>> {
+        Err(Error::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Moves the entry `from` in this directory to `to` in `to_directory`, replacing what is there.
+    fn rename(&self, _from: &str, _to_directory: &Arc<dyn Inode>, _to: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes out anything cached, for filesystems on disks.
+    fn sync(&self) {}
+}
+
+struct Mount {
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Mounts the initrd as the root, devices on /dev and a tmpfs on /tmp.
+pub fn init() {
+    mount("/", Arc::new(initrd::InitrdFs::new())).unwrap();
+    mount("/dev", Arc::new(devfs::DevFs)).unwrap();
+    mount("/tmp", Arc::new(tmpfs::TmpFs::new())).unwrap();
+}
+
+/// Mounts every block device that holds a filesystem we know on /mnt/<device>.
+pub fn mount_devices() {
+    for (name, device) in crate::block::devices() {
+        let Some((fs, kind)) = probe(device) else {
+            continue;
+        };
+        if mount(&format!("/mnt/{}", name), fs).is_ok() {
+            info!("Mounted {} on /mnt/{} ({})", name, name, kind);
+        }
+    }
+}
+
+fn probe(device: Arc<dyn crate::block::BlockDevice>) -> Option<(Arc<dyn FileSystem>, String)> {
+    if let Ok(fat) = fat::FatFs::new(device.clone()) {
+        let kind = format!("{:?}", fat.fat_type());
+        return Some((Arc::new(fat), kind));
+    }
+    if let Ok(ext2) = ext2::Ext2Fs::new(device) {
+        return Some((Arc::new(ext2), String::from("ext2")));
+    }
+    None
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let path: Vec<String> = components(path)?.into_iter().map(String::from).collect();
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(Error::AlreadyExists);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<()> {
+    let path = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().position(|mount| mount.path == path).ok_or(Error::NotFound)?;
+    mounts.remove(index).fs.sync();
+    Ok(())
+}
+
+pub fn sync() {
+    for mount in MOUNTS.lock().iter() {
+        mount.fs.sync();
+    }
+}
+
+// the names in a path, with "." and ".." resolved
+fn components(path: &str) -> Result<Vec<&str>> {
+    if !path.starts_with('/') {
+        return Err(Error::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {},
+            ".." => { components.pop(); },
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+fn is_prefix<A: PartialEq<B>, B>(prefix: &[A], path: &[B]) -> bool {
+    prefix.len() <= path.len() && prefix.iter().zip(path).all(|(a, b)| a == b)
+}
+
+// index into MOUNTS of the filesystem a path is on
+fn mount_of(path: &str) -> Result<usize> {
+    let components = components(path)?;
+    MOUNTS.lock().iter()
+        .enumerate()
+        .filter(|(_, mount)| is_prefix(&mount.path, &components))
+        .max_by_key(|(_, mount)| mount.path.len())
+        .map(|(index, _)| index)
+        .ok_or(Error::NotFound)
+}
+
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>> {
+    resolve(path, 0)
+}
+
+// Symbolic links are followed anywhere in the path, the last name included.
+// The rest of the path is appended to the link target and looked up again.
+fn resolve(path: &str, links_followed: usize) -> Result<Arc<dyn Inode>> {
+    let components = components(path)?;
+    let index = mount_of(path)?;
+    let (mount_depth, mut node) = {
+        let mounts = MOUNTS.lock();
+        (mounts[index].path.len(), mounts[index].fs.root())
+    };
+
+    for (position, name) in components.iter().enumerate().skip(mount_depth) {
+        node = node.lookup(name)?;
+        if node.metadata().kind == Kind::Symlink {
+            if links_followed == MAX_SYMLINKS {
+                return Err(Error::InvalidPath);
+            }
+            let target = node.read_link()?;
+            let directory = match target.starts_with('/') {
+                true => String::new(),
+                false => components[..position].join("/"),
+            };
+            let rest = components[position + 1..].join("/");
+            return resolve(&format!("/{}/{}/{}", directory, target, rest), links_followed + 1);
+        }
+    }
+    Ok(node)
+}
+
+// the directory a path is in, and its last name
+fn lookup_parent(path: &str) -> Result<(Arc<dyn Inode>, String)> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(Error::InvalidPath)?.to_string();
+    let parent = String::from("/") + &components.join("/");
+    Ok((lookup(&parent)?, name))
+}
+
+pub fn metadata(path: &str) -> Result<Metadata> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// Lists a directory, including filesystems mounted directly inside it.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>> {
+    let mut entries = lookup(path)?.read_dir()?;
+    let components = components(path)?;
+    for mount in MOUNTS.lock().iter() {
+        if mount.path.len() == components.len() + 1 && is_prefix(&components, &mount.path) {
+            let name = mount.path.last().unwrap();
+            if !entries.iter().any(|entry| &entry.name == name) {
+                entries.push(DirEntry { name: name.clone(), kind: Kind::Directory });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn create_dir(path: &str) -> Result<()> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.create(&name, Kind::Directory).map(|_| ())
+}
+
+pub fn remove(path: &str) -> Result<()> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.remove(&name)
+}
+
+/// Both paths have to be on the same mounted filesystem.
+pub fn rename(from: &str, to: &str) -> Result<()> {
+    if mount_of(from)? != mount_of(to)? {
+        return Err(Error::CrossDevice);
+    }
+    let (from_parent, from_name) = lookup_parent(from)?;
+    let (to_parent, to_name) = lookup_parent(to)?;
+    from_parent.rename(&from_name, &to_parent, &to_name)
+}
+
+pub fn open(path: &str, flags: u32) -> Result<Arc<File>> {
+    let inode = match lookup(path) {
+        Err(Error::NotFound) if flags & CREATE != 0 => {
+            let (parent, name) = lookup_parent(path)?;
+            parent.create(&name, Kind::File)?
+        },
+        result => result?,
+    };
+
+    let kind = inode.metadata().kind;
+    if kind == Kind::Directory && flags & (WRITE | APPEND) != 0 {
+        return Err(Error::IsADirectory);
+    }
+    if kind == Kind::File && flags & TRUNCATE != 0 {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(File { inode, flags, offset: Mutex::new(0) }))
+}
+
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+/// An open file with its own offset. File descriptors share one of these after a dup.
+pub struct File {
+    inode: Arc<dyn Inode>,
+    flags: u32,
+    offset: Mutex<usize>,
+}
+
+impl File {
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        if self.flags & READ == 0 {
+            return Err(Error::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        let read = self.inode.read_at(*offset, buffer)?;
+        *offset += read;
+        Ok(read)
+    }
+
+    pub fn write(&self, buffer: &[u8]) -> Result<usize> {
+        if self.flags & (WRITE | APPEND) == 0 {
+            return Err(Error::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags & APPEND != 0 {
+            *offset = self.inode.metadata().size;
+        }
+        let written = self.inode.write_at(*offset, buffer)?;
+        *offset += written;
+        Ok(written)
+    }
+
+    pub fn seek(&self, position: SeekFrom) -> Result<usize> {
+        let mut offset = self.offset.lock();
+        let new = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.inode.metadata().size.checked_add_signed(delta),
+        };
+        *offset = new.ok_or(Error::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    pub fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        self.inode.read_dir()
+    }
+
+    /// Reads everything from the current offset on.
+    pub fn read_to_end(&self) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        let mut buffer = [0; 512];
+        loop {
+            match self.read(&mut buffer)? {
+                0 => return Ok(contents),
+                read => contents.extend_from_slice(&buffer[..read]),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn components_resolve_dots() {
+        assert_eq!(components("/a/./b/../c/").unwrap(), ["a", "c"]);
+        assert_eq!(components("/..").unwrap(), [] as [&str; 0]);
+        assert_eq!(components("relative"), Err(Error::InvalidPath));
+    }
+
+    #[test_case]
+    fn tmpfs_round_trip() {
+        let file = open("/tmp/test_case", CREATE | READ | WRITE).unwrap();
+        file.write(b"hello").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read_to_end().unwrap(), b"hello");
+        remove("/tmp/test_case").unwrap();
+        assert!(open("/tmp/test_case", READ).is_err());
+    }
+}
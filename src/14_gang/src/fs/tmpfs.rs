@@ -0,0 +1,186 @@
+//! Writable filesystem kept entirely on the heap
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::BTreeMap;
+use core::any::Any;
+use super::{DirEntry, Error, FileSystem, Inode, Kind, Metadata, Result};
+
+pub struct TmpFs {
+    root: Arc<Node>,
+}
+
+struct Node(Mutex<Contents>);
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<Node>>),
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        TmpFs { root: Node::new(Kind::Directory) }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl Node {
+    fn new(kind: Kind) -> Arc<Node> {
+        let contents = match kind {
+            Kind::Directory => Contents::Directory(BTreeMap::new()),
+            _ => Contents::File(Vec::new()),
+        };
+        Arc::new(Node(Mutex::new(contents)))
+    }
+
+    // whether `node` is this directory or anywhere below it
+    fn contains(self: &Arc<Self>, node: &Arc<Node>) -> bool {
+        if Arc::ptr_eq(self, node) {
+            return true;
+        }
+        match &*self.0.lock() {
+            Contents::Directory(children) => children.values().any(|child| child.contains(node)),
+            Contents::File(_) => false,
+        }
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        match &*self.0.lock() {
+            Contents::File(data) => Metadata { kind: Kind::File, size: data.len() },
+            Contents::Directory(children) => Metadata { kind: Kind::Directory, size: children.len() },
+        }
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize> {
+        let Contents::File(data) = &*self.0.lock() else {
+            return Err(Error::IsADirectory);
+        };
+        let data = data.get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    // writing past the end leaves a zeroed gap
+    fn write_at(&self, offset: usize, buffer: &[u8]) -> Result<usize> {
+        let Contents::File(data) = &mut *self.0.lock() else {
+            return Err(Error::IsADirectory);
+        };
+        let end = offset.checked_add(buffer.len()).ok_or(Error::InvalidArgument)?;
+        if end > data.len() {
+            data.try_reserve(end - data.len()).map_err(|_| Error::NoSpace)?;
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: usize) -> Result<()> {
+        let Contents::File(data) = &mut *self.0.lock() else {
+            return Err(Error::IsADirectory);
+        };
+        data.try_reserve(size.saturating_sub(data.len())).map_err(|_| Error::NoSpace)?;
+        data.resize(size, 0);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let Contents::Directory(children) = &*self.0.lock() else {
+            return Err(Error::NotADirectory);
+        };
+        children.get(name).map(|node| node.clone() as Arc<dyn Inode>).ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let Contents::Directory(children) = &*self.0.lock() else {
+            return Err(Error::NotADirectory);
+        };
+        Ok(children.iter()
+            .map(|(name, node)| DirEntry { name: name.clone(), kind: node.metadata().kind })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: Kind) -> Result<Arc<dyn Inode>> {
+        let Contents::Directory(children) = &mut *self.0.lock() else {
+            return Err(Error::NotADirectory);
+        };
+        if children.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        let node = Node::new(kind);
+        children.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let Contents::Directory(children) = &mut *self.0.lock() else {
+            return Err(Error::NotADirectory);
+        };
+        let node = children.get(name).ok_or(Error::NotFound)?;
+        if node.metadata().kind == Kind::Directory && node.metadata().size > 0 {
+            return Err(Error::NotEmpty);
+        }
+        children.remove(name);
+        Ok(())
+    }
+
+    // open files keep their node, so renaming or removing doesn't disturb them
+    fn rename(&self, from: &str, to_directory: &Arc<dyn Inode>, to: &str) -> Result<()> {
+        let any: Arc<dyn Any + Send + Sync> = to_directory.clone();
+        let target = any.downcast::<Node>().map_err(|_| Error::CrossDevice)?;
+        if core::ptr::eq(self, &*target) {
+            let Contents::Directory(children) = &mut *self.0.lock() else {
+                return Err(Error::NotADirectory);
+            };
+            let node = children.get(from).ok_or(Error::NotFound)?.clone();
+            replace(children, to, node)?;
+            if from != to {
+                children.remove(from);
+            }
+            return Ok(());
+        }
+
+        let node = match &*self.0.lock() {
+            Contents::Directory(children) => children.get(from).ok_or(Error::NotFound)?.clone(),
+            Contents::File(_) => return Err(Error::NotADirectory),
+        };
+        if node.contains(&target) {
+            return Err(Error::InvalidArgument); // a directory can't move inside itself
+        }
+        match &mut *target.0.lock() {
+            Contents::Directory(children) => replace(children, to, node.clone())?,
+            Contents::File(_) => return Err(Error::NotADirectory),
+        }
+        if let Contents::Directory(children) = &mut *self.0.lock() {
+            children.remove(from);
+        }
+        Ok(())
+    }
+}
+
+// puts `node` at `name`, over whatever is there as long as both are files or the old one is an empty directory
+fn replace(children: &mut BTreeMap<String, Arc<Node>>, name: &str, node: Arc<Node>) -> Result<()> {
+    if let Some(existing) = children.get(name) {
+        if Arc::ptr_eq(existing, &node) {
+            return Ok(());
+        }
+        let (old, new) = (existing.metadata(), node.metadata());
+        match (old.kind, new.kind) {
+            (Kind::Directory, Kind::Directory) if old.size > 0 => return Err(Error::NotEmpty),
+            (Kind::Directory, Kind::Directory) => {},
+            (Kind::Directory, _) => return Err(Error::IsADirectory),
+            (_, Kind::Directory) => return Err(Error::NotADirectory),
+            _ => {},
+        }
+    }
+    children.insert(String::from(name), node);
+    Ok(())
+}
@@ -0,0 +1,491 @@
+//! GDB remote serial protocol stub on COM2
+//!
+//! Breakpoints, single steps, Ctrl-C from gdb (a 0x03 byte) and gdb attaching
+//! stop the CPU they happen on and hand it to gdb until it continues. Other
+//! CPUs keep running. Connect with `target remote` to whatever COM2 is attached to.
+//!
+//! Nothing here allocates or takes locks outside the stub's own, since the CPU
+//! may have stopped anywhere, the heap included.
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use log::info;
+use crate::memory::paging;
+use crate::serial::{SerialPort, COM2, DEFAULT_BAUD};
+use crate::interrupts::{idt::InterruptIndex, pic::PICS};
+
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+const TRAP_FLAG: u64 = 1 << 8;
+const CTRL_C: u8 = 0x03;
+
+// signals in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    port: SerialPort::new(COM2),
+    breakpoints: [None; MAX_BREAKPOINTS],
+    input: [0; PACKET_SIZE],
+    output: Output { bytes: [0; PACKET_SIZE], len: 0 },
+});
+
+/// Everything the entry stubs below save, lowest address first.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    r15: u64, r14: u64, r13: u64, r12: u64, r11: u64, r10: u64, r9: u64, r8: u64,
+    rbp: u64, rdi: u64, rsi: u64, rdx: u64, rcx: u64, rbx: u64, rax: u64,
+    vector: u64,
+    error_code: u64,
+    // pushed by the CPU
+    rip: u64, cs: u64, rflags: u64, rsp: u64, ss: u64,
+}
+
+// Debug, breakpoint and COM2 interrupts all need every register, so they get
+// their own entry instead of the x86-interrupt ABI. The dummy error code keeps
+// the frame the same shape for all of them and the stack 16 byte aligned.
+global_asm!(
+    ".global gdb_debug_entry",
+    "gdb_debug_entry:",
+    "push 0",
+    "push {debug}",
+    "jmp gdb_common_entry",
+
+    ".global gdb_breakpoint_entry",
+    "gdb_breakpoint_entry:",
+    "push 0",
+    "push {breakpoint}",
+    "jmp gdb_common_entry",
+
+    ".global gdb_com2_entry",
+    "gdb_com2_entry:",
+    "push 0",
+    "push {com2}",
+    "jmp gdb_common_entry",
+
+    "gdb_common_entry:",
+    "test byte ptr [rsp + 24], 3",      // cs, past the vector and error code
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "push rax\npush rbx\npush rcx\npush rdx\npush rsi\npush rdi\npush rbp",
+    "push r8\npush r9\npush r10\npush r11\npush r12\npush r13\npush r14\npush r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {trap}",
+    "pop r15\npop r14\npop r13\npop r12\npop r11\npop r10\npop r9\npop r8",
+    "pop rbp\npop rdi\npop rsi\npop rdx\npop rcx\npop rbx\npop rax",
+    "add rsp, 16", // vector and error code
+    "test byte ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "iretq",
+    debug = const InterruptIndex::Debug as u8,
+    breakpoint = const InterruptIndex::Breakpoint as u8,
+    com2 = const InterruptIndex::Com2 as u8,
+    trap = sym trap,
+);
+
+extern "C" {
+    pub fn gdb_debug_entry();
+    pub fn gdb_breakpoint_entry();
+    pub fn gdb_com2_entry();
+}
+
+/// Sets up COM2 for gdb, if there is one.
+pub fn init() {
+    let mut stub = STUB.lock();
+    if stub.port.init(DEFAULT_BAUD) {
+        stub.port.enable_receive_interrupt();
+        PICS.lock().unmask(3);
+        ENABLED.store(true, Ordering::SeqCst);
+        info!("GDB stub on COM2");
+    }
+}
+
+/// Stops here and waits for gdb, if the stub is up.
+pub fn breakpoint() {
+    unsafe { core::arch::asm!("int3", options(nomem, nostack)) };
+}
+
+extern "C" fn trap(frame: &mut TrapFrame) {
+    match frame.vector as u8 {
+        vector if vector == InterruptIndex::Com2 as u8 => {
+            let _interrupt = crate::cpu::enter_interrupt();
+            let wake = match ENABLED.load(Ordering::SeqCst) {
+                true => STUB.lock().wake_reason(),
+                false => None,
+            };
+            PICS.lock().send_eoi(InterruptIndex::Com2);
+            match wake {
+                Some(CTRL_C) => STUB.lock().serve(frame, SIGINT, false, true),
+                Some(_) => {
+                    // gdb just attached and is talking to us, have it send that again once we listen
+                    let mut stub = STUB.lock();
+                    stub.port.send(b'-');
+                    stub.serve(frame, SIGINT, false, false);
+                },
+                None => {},
+            }
+        },
+        _ if !ENABLED.load(Ordering::SeqCst) => {
+            // nobody to hand it to, carry on after the int3 or step
+            if frame.vector as u8 == InterruptIndex::Breakpoint as u8 {
+                println!("\nEXCEPTION: BREAKPOINT at {:#x}", frame.rip - 1);
+            }
+            frame.rflags &= !TRAP_FLAG;
+        },
+        vector => {
+            let mut stub = STUB.lock();
+            // int3 leaves rip past itself, gdb wants it back on our own breakpoints
+            let ours = vector == InterruptIndex::Breakpoint as u8 && stub.breakpoint(frame.rip - 1).is_some();
+            if ours {
+                frame.rip -= 1;
+            }
+            stub.serve(frame, SIGTRAP, ours, true);
+        },
+    }
+}
+
+struct Stub {
+    port: SerialPort,
+    breakpoints: [Option<(u64, u8)>; MAX_BREAKPOINTS], // address and the byte int3 replaced
+    input: [u8; PACKET_SIZE],
+    output: Output,
+}
+
+// a reply being put together
+struct Output {
+    bytes: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl Output {
+    fn hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let _ = write!(self, "{:02x}", byte);
+        }
+    }
+}
+
+impl Stub {
+    // Ctrl-C or the start of a packet while running, anything else is a stray ack
+    fn wake_reason(&mut self) -> Option<u8> {
+        let mut reason = None;
+        while let Some(byte) = self.port.receive() {
+            if byte == CTRL_C || byte == b'$' {
+                reason = reason.or(Some(byte));
+            }
+        }
+        reason
+    }
+
+    // Talks to gdb until it continues or steps. `announce` sends the stop
+    // reply gdb is waiting for after a continue, step or Ctrl-C.
+    fn serve(&mut self, frame: &mut TrapFrame, signal: u8, swbreak: bool, announce: bool) {
+        frame.rflags &= !TRAP_FLAG;
+        if announce {
+            self.stop_reply(signal, swbreak);
+            self.send_packet();
+        }
+        loop {
+            let len = self.receive_packet();
+            self.output.len = 0;
+            let kind = self.input[..len].first().copied();
+            let resume = match kind {
+                Some(b'c') | Some(b's') => {
+                    if let Some(address) = hex_value(&self.input[1..len]) {
+                        frame.rip = address;
+                    }
+                    if kind == Some(b's') {
+                        frame.rflags |= TRAP_FLAG;
+                    }
+                    true
+                },
+                Some(b'D') | Some(b'k') => {
+                    self.remove_all_breakpoints();
+                    if kind == Some(b'D') {
+                        let _ = self.output.write_str("OK");
+                    }
+                    true
+                },
+                Some(b'?') => {
+                    self.stop_reply(signal, swbreak);
+                    false
+                },
+                _ => {
+                    let packet = &self.input[..len];
+                    let (breakpoints, output) = (&mut self.breakpoints, &mut self.output);
+                    if command(packet, frame, breakpoints, output).is_err() {
+                        output.len = 0;
+                        let _ = output.write_str("E01");
+                    }
+                    false
+                },
+            };
+            // continuing, stepping and kill get no reply until the next stop
+            if !resume || self.output.len > 0 {
+                self.send_packet();
+            }
+            if resume {
+                return;
+            }
+        }
+    }
+
+    fn stop_reply(&mut self, signal: u8, swbreak: bool) {
+        self.output.len = 0;
+        let _ = match swbreak {
+            true => write!(self.output, "T{:02x}swbreak:;", signal),
+            false => write!(self.output, "S{:02x}", signal),
+        };
+    }
+
+    fn breakpoint(&self, address: u64) -> Option<usize> {
+        self.breakpoints.iter().position(|breakpoint| matches!(breakpoint, Some((at, _)) if *at == address))
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for (address, original) in self.breakpoints.iter_mut().filter_map(Option::take) {
+            let _ = write_memory(address, &[original]);
+        }
+    }
+
+    // $data#checksum, acked with + or - for a resend
+    fn receive_packet(&mut self) -> usize {
+        loop {
+            while read_byte(&mut self.port) != b'$' {}
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                match read_byte(&mut self.port) {
+                    b'#' => break,
+                    byte => {
+                        if len < PACKET_SIZE {
+                            self.input[len] = byte;
+                            len += 1;
+                        }
+                        checksum = checksum.wrapping_add(byte);
+                    },
+                }
+            }
+            let expected = [read_byte(&mut self.port), read_byte(&mut self.port)];
+            if hex_value(&expected) == Some(checksum as u64) && len < PACKET_SIZE {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    fn send_packet(&mut self) {
+        let data = &self.output.bytes[..self.output.len];
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            self.port.send(b'$');
+            for &byte in data {
+                self.port.send(byte);
+            }
+            self.port.send(b'#');
+            for digit in [checksum >> 4, checksum & 0xf] {
+                self.port.send(b"0123456789abcdef"[digit as usize]);
+            }
+            match read_byte(&mut self.port) {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+fn read_byte(port: &mut SerialPort) -> u8 {
+    loop {
+        if let Some(byte) = port.receive() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Everything that doesn't resume. Unsupported packets get an empty reply.
+fn command(packet: &[u8], frame: &mut TrapFrame, breakpoints: &mut [Option<(u64, u8)>], output: &mut Output) -> Result<(), ()> {
+    let Some((&kind, arguments)) = packet.split_first() else {
+        return Ok(());
+    };
+    match kind {
+        b'g' => {
+            for number in 0..REGISTER_COUNT {
+                let (value, size) = register(frame, number).ok_or(())?;
+                output.hex(&value.to_le_bytes()[..size]);
+            }
+        },
+        b'G' => {
+            let mut offset = 0;
+            for number in 0..REGISTER_COUNT {
+                let size = register(frame, number).ok_or(())?.1;
+                let Some(hex) = arguments.get(offset..offset + size * 2) else {
+                    break;
+                };
+                set_register(frame, number, le_hex_value(hex)?);
+                offset += size * 2;
+            }
+            output.write_str("OK").map_err(|_| ())?;
+        },
+        b'p' => {
+            let number = hex_value(arguments).ok_or(())? as usize;
+            let (value, size) = register(frame, number).ok_or(())?;
+            output.hex(&value.to_le_bytes()[..size]);
+        },
+        b'P' => {
+            let (number, value) = split(arguments, b'=')?;
+            set_register(frame, hex_value(number).ok_or(())? as usize, le_hex_value(value)?);
+            output.write_str("OK").map_err(|_| ())?;
+        },
+        b'm' => {
+            let (address, len) = split(arguments, b',')?;
+            let (address, len) = (hex_value(address).ok_or(())?, hex_value(len).ok_or(())? as usize);
+            let mut buffer = [0; PACKET_SIZE / 2 - 1];
+            let bytes = buffer.get_mut(..len).ok_or(())?;
+            read_memory(address, bytes)?;
+            output.hex(bytes);
+        },
+        b'M' => {
+            let (location, data) = split(arguments, b':')?;
+            let (address, len) = split(location, b',')?;
+            let (address, len) = (hex_value(address).ok_or(())?, hex_value(len).ok_or(())? as usize);
+            let mut buffer = [0; PACKET_SIZE / 2];
+            let bytes = buffer.get_mut(..len).ok_or(())?;
+            if data.len() != len * 2 {
+                return Err(()); // anything short would write zeros
+            }
+            for (byte, hex) in bytes.iter_mut().zip(data.chunks(2)) {
+                *byte = hex_value(hex).ok_or(())? as u8;
+            }
+            write_memory(address, bytes)?;
+            output.write_str("OK").map_err(|_| ())?;
+        },
+        // software breakpoints only, anything else is unsupported
+        b'Z' if arguments.starts_with(b"0,") => {
+            let (address, _kind) = split(&arguments[2..], b',')?;
+            let address = hex_value(address).ok_or(())?;
+            if !breakpoints.iter().flatten().any(|&(at, _)| at == address) {
+                let slot = breakpoints.iter_mut().find(|slot| slot.is_none()).ok_or(())?;
+                let mut original = [0];
+                read_memory(address, &mut original)?;
+                write_memory(address, &[INT3])?;
+                *slot = Some((address, original[0]));
+            }
+            output.write_str("OK").map_err(|_| ())?;
+        },
+        b'z' if arguments.starts_with(b"0,") => {
+            let (address, _kind) = split(&arguments[2..], b',')?;
+            let address = hex_value(address).ok_or(())?;
+            let slot = breakpoints.iter_mut().find(|slot| matches!(slot, Some((at, _)) if *at == address));
+            if let Some((address, original)) = slot.and_then(Option::take) {
+                write_memory(address, &[original])?;
+            }
+            output.write_str("OK").map_err(|_| ())?;
+        },
+        b'q' if packet.starts_with(b"qSupported") => {
+            write!(output, "PacketSize={:x};swbreak+", PACKET_SIZE).map_err(|_| ())?;
+        },
+        b'q' if packet.starts_with(b"qAttached") => output.write_str("1").map_err(|_| ())?,
+        b'H' => output.write_str("OK").map_err(|_| ())?,
+        _ => {},
+    }
+    Ok(())
+}
+
+// gdb's amd64 order: rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15, rip, then
+// eflags, cs, ss, ds, es, fs and gs as 32 bit values
+const REGISTER_COUNT: usize = 24;
+
+fn register(frame: &TrapFrame, number: usize) -> Option<(u64, usize)> {
+    let value = match number {
+        0 => frame.rax, 1 => frame.rbx, 2 => frame.rcx, 3 => frame.rdx,
+        4 => frame.rsi, 5 => frame.rdi, 6 => frame.rbp, 7 => frame.rsp,
+        8 => frame.r8, 9 => frame.r9, 10 => frame.r10, 11 => frame.r11,
+        12 => frame.r12, 13 => frame.r13, 14 => frame.r14, 15 => frame.r15,
+        16 => frame.rip,
+        17 => return Some((frame.rflags, 4)),
+        18 => return Some((frame.cs, 4)),
+        19 => return Some((frame.ss, 4)),
+        20..=23 => return Some((0, 4)), // data segments are all null in long mode
+        _ => return None,
+    };
+    Some((value, 8))
+}
+
+// segment registers stay as they are
+fn set_register(frame: &mut TrapFrame, number: usize, value: u64) {
+    let register = match number {
+        0 => &mut frame.rax, 1 => &mut frame.rbx, 2 => &mut frame.rcx, 3 => &mut frame.rdx,
+        4 => &mut frame.rsi, 5 => &mut frame.rdi, 6 => &mut frame.rbp, 7 => &mut frame.rsp,
+        8 => &mut frame.r8, 9 => &mut frame.r9, 10 => &mut frame.r10, 11 => &mut frame.r11,
+        12 => &mut frame.r12, 13 => &mut frame.r13, 14 => &mut frame.r14, 15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        _ => return,
+    };
+    *register = value;
+}
+
+// unmapped memory is an error instead of a page fault
+fn read_memory(address: u64, bytes: &mut [u8]) -> Result<(), ()> {
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(checked(address, offset, false)? as *const u8) };
+    }
+    Ok(())
+}
+
+fn write_memory(address: u64, bytes: &[u8]) -> Result<(), ()> {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        unsafe { core::ptr::write_volatile(checked(address, offset, true)? as *mut u8, byte) };
+    }
+    Ok(())
+}
+
+// a fault in here would happen with STUB locked, so anything that could fault is an error instead
+fn checked(address: u64, offset: usize, write: bool) -> Result<usize, ()> {
+    let address = (address as usize).checked_add(offset).ok_or(())?;
+    let p4 = paging::active_p4();
+    match write {
+        true => paging::is_writable(p4, address).then_some(address).ok_or(()),
+        false => paging::translate(p4, address).map(|_| address).ok_or(()),
+    }
+}
+
+fn split(bytes: &[u8], separator: u8) -> Result<(&[u8], &[u8]), ()> {
+    let at = bytes.iter().position(|&byte| byte == separator).ok_or(())?;
+    Ok((&bytes[..at], &bytes[at + 1..]))
+}
+
+fn hex_value(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0, |value, &digit| Some(value << 4 | (digit as char).to_digit(16)? as u64))
+}
+
+// registers go over the wire in target byte order
+fn le_hex_value(hex: &[u8]) -> Result<u64, ()> {
+    let mut bytes = [0; 8];
+    for (byte, digits) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = hex_value(digits).ok_or(())? as u8;
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,106 @@
+//! Read-only filesystem out of a ustar archive loaded by GRUB as a boot module
+use log::warn;
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::string::String;
+use multiboot2::BootInformation;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: String, // without a leading slash, "songs/tetris"
+    pub kind: Kind,
+    pub data: &'static [u8],
+}
+
+static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Parses the first boot module, if there is one. Module memory is identity mapped
+/// and never reused, so file contents are borrowed from it directly.
+pub fn init(boot_info: &BootInformation) {
+    let Some(module) = boot_info.module_tags().next() else {
+        return;
+    };
+    let start = module.start_address() as usize;
+    let archive = unsafe { core::slice::from_raw_parts(start as *const u8, module.module_size() as usize) };
+    *ENTRIES.lock() = parse(archive);
+}
+
+/// End of the memory used by boot modules, which nothing else may allocate.
+pub fn modules_end(boot_info: &BootInformation) -> usize {
+    boot_info.module_tags().map(|module| module.end_address() as usize).max().unwrap_or(0)
+}
+
+pub fn entries() -> Vec<Entry> {
+    ENTRIES.lock().clone()
+}
+
+pub fn read(path: &str) -> Option<&'static [u8]> {
+    let path = path.trim_start_matches('/');
+    ENTRIES.lock().iter()
+        .find(|entry| entry.kind == Kind::File && entry.path == path)
+        .map(|entry| entry.data)
+}
+
+fn parse(archive: &'static [u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            break; // the archive ends with two zeroed blocks
+        }
+        if &header[257..262] != b"ustar" {
+            warn!("Not a ustar archive");
+            break;
+        }
+
+        let size = octal(&header[124..136]);
+        let data_start = offset + BLOCK_SIZE;
+        let Some(data) = archive.get(data_start..data_start + size) else {
+            warn!("Archive is truncated");
+            break;
+        };
+
+        let kind = match header[156] {
+            b'0' | 0 => Some(Kind::File),
+            b'5' => Some(Kind::Directory),
+            _ => None, // links and such aren't supported
+        };
+        let mut path = String::new();
+        let prefix = string(&header[345..500]);
+        if !prefix.is_empty() {
+            path.push_str(prefix);
+            path.push('/');
+        }
+        path.push_str(string(&header[0..100]));
+        let path = path.trim_start_matches("./").trim_end_matches('/');
+
+        if let Some(kind) = kind.filter(|_| !path.is_empty() && path != ".") {
+            entries.push(Entry { path: String::from(path), kind, data });
+        }
+        offset = data_start + crate::util::align_up(size, BLOCK_SIZE);
+    }
+    entries
+}
+
+// fields are nul terminated, unless they fill the whole field
+fn string(field: &[u8]) -> &str {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+fn octal(field: &[u8]) -> usize {
+    field.iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|byte| (b'0'..=b'7').contains(byte))
+        .fold(0, |value, &byte| value * 8 + (byte - b'0') as usize)
+}
@@ -1,32 +1,21 @@
 use core::arch::asm;
 use bit_field::BitField;
-use lazy_static::lazy_static;
-use core::mem::{size_of, zeroed};
+use core::mem::{offset_of, size_of, zeroed};
 use super::{TablePointer, Address};
 
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+pub const TSS_RSP0_OFFSET: usize = offset_of!(Tss, rsp); // stack for traps from ring 3
 
-lazy_static! {
-    static ref TSS: Tss = {
-        let mut tss = Tss::new();
-        // stack for the double fault handler
-        tss.ist[DOUBLE_FAULT_IST_INDEX] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_end = unsafe { STACK.as_ptr() as u64 + STACK_SIZE as u64 };
-            Address(stack_end)
-        };
-        tss
-    };
+// laid out like SYSRET expects: user data right below user code, see Gdt::with_tss
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, 0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, 3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, 3);
 
-    pub static ref GDT: Gdt = {
-        let mut gdt = Gdt::new();
-        let code = gdt.add_entry(Descriptor::UserSegment(0x20980000000000));
-        let tss = gdt.add_entry(TSS.descriptor());
-        gdt.selectors = Selectors {code, tss};
-        gdt
-    };
-}
+const KERNEL_CODE: u64 = 0x20980000000000; // present, code, long mode
+const KERNEL_DATA: u64 = 0x920000000000; // present, data, writable
+const USER_CODE: u64 = KERNEL_CODE | 3 << 45; // same, but DPL 3
+const USER_DATA: u64 = KERNEL_DATA | 3 << 45;
 
 enum Descriptor {
     UserSegment(u64),
@@ -35,9 +24,9 @@ enum Descriptor {
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
-struct Tss {
+pub struct Tss {
     _reserved_1: u32,
-    _rsp: [Address; 3],
+    rsp: [Address; 3],
     _reserved_2: u64,
     ist: [Address; 7],
     _reserved_3: u64,
@@ -53,6 +42,12 @@ impl Tss {
         }
     }
 
+    pub fn with_double_fault_stack(stack_bottom: u64) -> Tss {
+        let mut tss = Tss::new();
+        tss.ist[DOUBLE_FAULT_IST_INDEX] = Address(stack_bottom + DOUBLE_FAULT_STACK_SIZE as u64);
+        tss
+    }
+
     fn descriptor(&self) -> Descriptor {
         let ptr = self as *const _ as u64;
         let mut low = 1 << 47;
@@ -70,8 +65,8 @@ impl Tss {
 pub struct SegmentSelector(pub u16);
 
 impl SegmentSelector {
-    pub const fn new(index: u16) -> SegmentSelector {
-        SegmentSelector(index << 3)
+    pub const fn new(index: u16, privilege_level: u16) -> SegmentSelector {
+        SegmentSelector(index << 3 | privilege_level)
     }
 }
 
@@ -87,13 +82,24 @@ pub struct Gdt {
 }
 
 impl Gdt {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Gdt {
             next: 1,
             ..unsafe { zeroed() }
         }
     }
 
+    pub fn with_tss(tss: &Tss) -> Self {
+        let mut gdt = Gdt::new();
+        let code = gdt.add_entry(Descriptor::UserSegment(KERNEL_CODE));
+        gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA));
+        gdt.add_entry(Descriptor::UserSegment(USER_DATA));
+        gdt.add_entry(Descriptor::UserSegment(USER_CODE));
+        let tss = gdt.add_entry(tss.descriptor());
+        gdt.selectors = Selectors {code, tss};
+        gdt
+    }
+
     fn push(&mut self, value: u64) -> usize {
         let index = self.next;
         self.table[index] = value;
@@ -102,15 +108,15 @@ impl Gdt {
     }
 
     fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
-        let index = match entry {
-            Descriptor::UserSegment(value) => self.push(value),
+        let (index, privilege_level) = match entry {
+            Descriptor::UserSegment(value) => (self.push(value), value.get_bits(45..47)),
             Descriptor::SystemSegment(value_low, value_high) => {
                 let index = self.push(value_low);
                 self.push(value_high);
-                index
+                (index, 0)
             },
         };
-        SegmentSelector::new(index as u16)
+        SegmentSelector::new(index as u16, privilege_level as u16)
     }
 
     fn load(&self) {
@@ -126,29 +132,27 @@ impl Gdt {
             );
         }
     }
-}
 
-pub fn init() {
-    let gdt = &GDT;
-
-    gdt.load();
-
-    // cs and tss
-    unsafe {
-        asm!(
-            "push {sel}",
-            "lea {tmp}, [1f + rip]",
-            "push {tmp}",
-            "retfq",
-            "1:",
-            sel = in(reg) u64::from(gdt.selectors.code.0),
-            tmp = lateout(reg) _,
-            options(preserves_flags),
-        );
-        asm!(
-            "ltr {0:x}",
-            in(reg) gdt.selectors.tss.0,
-            options(preserves_flags),
-        );
+    pub fn activate(&self) {
+        self.load();
+
+        // cs and tss
+        unsafe {
+            asm!(
+                "push {sel}",
+                "lea {tmp}, [1f + rip]",
+                "push {tmp}",
+                "retfq",
+                "1:",
+                sel = in(reg) u64::from(self.selectors.code.0),
+                tmp = lateout(reg) _,
+                options(preserves_flags),
+            );
+            asm!(
+                "ltr {0:x}",
+                in(reg) self.selectors.tss.0,
+                options(preserves_flags),
+            );
+        }
     }
-}
\ No newline at end of file
+}
@@ -7,33 +7,71 @@ use super::idt::InterruptIndex;
 use core::sync::atomic::Ordering;
 use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
-pub extern "x86-interrupt" fn divide_error() {
+/// What the CPU pushes when an interrupt comes in.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// A fault in ring 3 is the process's problem, not the kernel's, so it gets
+// killed and `run` returns to whoever waited for it.
+fn kill_if_from_user(frame: &InterruptStackFrame, exception: &str) {
+    if frame.cs & 3 == 3 {
+        log::warn!("Process {} killed by {} at {:#x}", crate::process::current(), exception, frame.rip);
+        super::enable(); // exit returns to kernel code that expects interrupts on, like after a syscall
+        crate::usermode::exit(crate::process::KILLED);
+    }
+}
+
+pub extern "x86-interrupt" fn divide_error(frame: InterruptStackFrame) {
+    kill_if_from_user(&frame, "divide error");
     print!("\nEXCEPTION: DIVIDE ERROR\n");
+    crate::backtrace::print();
     crate::util::hlt_loop();
 }
 
-pub extern "x86-interrupt" fn double_fault() {
+pub extern "x86-interrupt" fn non_maskable_interrupt() {
+    // another CPU panicked and wants everyone stopped
+    if crate::panic::panicking() {
+        crate::panic::halt();
+    }
+}
+
+pub extern "x86-interrupt" fn double_fault(_frame: InterruptStackFrame, _error_code: u64) {
     print!("\nEXCEPTION: DOUBLE FAULT\n");
+    crate::backtrace::print();
     crate::util::hlt_loop();
 }
 
-pub extern "x86-interrupt" fn general_protection_fault() {
+pub extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, _error_code: u64) {
+    kill_if_from_user(&frame, "general protection fault");
     print!("\nEXCEPTION: GENERAL PROTECTION FAULT\n");
+    crate::backtrace::print();
     crate::util::hlt_loop();
 }
 
-pub extern "x86-interrupt" fn page_fault() {
+pub extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, _error_code: u64) {
+    kill_if_from_user(&frame, "page fault");
     print!("\nEXCEPTION: PAGE FAULT\n");
+    crate::backtrace::print();
     crate::util::hlt_loop();
 }
 
 pub extern "x86-interrupt" fn timer_interrupt() {
+    let _interrupt = crate::cpu::enter_interrupt();
     // print!(".");
-    SYSTEM_TICKS.fetch_add(1, Ordering::SeqCst);
+    let ticks = SYSTEM_TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    super::pit::run_callbacks(ticks);
     PICS.lock().send_eoi(InterruptIndex::Timer);
 }
 
 pub extern "x86-interrupt" fn keyboard_interrupt() {
+    let _interrupt = crate::cpu::enter_interrupt();
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<No105Key, ScancodeSet1>> = Mutex::new(
             Keyboard::new(ScancodeSet1::new(), No105Key, HandleControl::Ignore)
@@ -47,11 +85,39 @@ pub extern "x86-interrupt" fn keyboard_interrupt() {
         if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
             match character {
                 '\n' => println!(),
-                _ if (0x20..=0x7e).contains(&(character as u32)) => print!("{}", character),
+                _ if !character.is_control() => print!("{}", character),
                 _ => {}
             }
         }
     }
     
     PICS.lock().send_eoi(InterruptIndex::Keyboard);
-}
\ No newline at end of file
+}
+
+pub extern "x86-interrupt" fn com1_interrupt() {
+    let _interrupt = crate::cpu::enter_interrupt();
+    crate::serial::interrupt();
+    PICS.lock().send_eoi(InterruptIndex::Com1);
+}
+
+pub extern "x86-interrupt" fn primary_ata_interrupt() {
+    let _interrupt = crate::cpu::enter_interrupt();
+    crate::block::ata::interrupt(0);
+    PICS.lock().send_eoi(InterruptIndex::PrimaryAta);
+}
+
+pub extern "x86-interrupt" fn secondary_ata_interrupt() {
+    let _interrupt = crate::cpu::enter_interrupt();
+    crate::block::ata::interrupt(1);
+    PICS.lock().send_eoi(InterruptIndex::SecondaryAta);
+}
+
+pub extern "x86-interrupt" fn lapic_timer_interrupt() {
+    // only here to wake application processors, the time comes from the PIT
+    let _interrupt = crate::cpu::enter_interrupt();
+    super::lapic::eoi();
+}
+
+pub extern "x86-interrupt" fn spurious_interrupt() {
+    // local apic spurious interrupts don't get an eoi
+}
@@ -1,20 +1,101 @@
-use core::arch::asm;
+use core::arch::{asm, global_asm};
 use super::handlers;
 use super::pic::PIC_OFFSET;
+use crate::gdb::{gdb_breakpoint_entry, gdb_com2_entry, gdb_debug_entry};
 use lazy_static::lazy_static;
 use core::ops::{Index, IndexMut};
 use super::{TablePointer, Address};
-use super::gdt::{SegmentSelector, DOUBLE_FAULT_IST_INDEX, GDT};
+use super::gdt::{SegmentSelector, DOUBLE_FAULT_IST_INDEX, KERNEL_CODE_SELECTOR};
+
+extern "C" {
+    fn syscall_interrupt(); // int 0x80 entry in syscall.rs
+}
+
+// Interrupts from ring 3 come in with the user's GS base, which the handlers
+// can't swap themselves. Their entry swaps it, then fakes a second interrupt
+// frame so the handler's iretq comes back here to swap it again on the way
+// out. From ring 0 the handler runs directly.
+//
+// Faults never go back to ring 3, the handler kills the process instead, so
+// they only swap on the way in and keep the real frame for the handler to look
+// at. `error_code` is 8 for the ones that push one.
+macro_rules! swapgs_entry {
+    ($entry:ident, $handler:path) => {
+        global_asm!(
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "test byte ptr [rsp + 8], 3",
+            "jz {handler}",
+            "swapgs",
+            "sub rsp, 8",                   // keeps the handler's stack aligned like the cpu would
+            "push 0",                       // ss
+            "push rsp",
+            "add qword ptr [rsp], 8",       // rsp, from before the push of ss
+            "pushfq",
+            "push {code}",                  // cs
+            "push rax",
+            "lea rax, [rip + 2f]",
+            "xchg rax, [rsp]",              // rip, without losing rax
+            "jmp {handler}",
+            "2:",
+            "add rsp, 8",                   // the padding
+            "swapgs",
+            "iretq",
+            code = const KERNEL_CODE_SELECTOR.0,
+            handler = sym $handler,
+        );
+        extern "C" {
+            fn $entry();
+        }
+    };
+    (fault $entry:ident, $handler:path, $error_code:literal) => {
+        global_asm!(
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "test byte ptr [rsp + 8 + {error_code}], 3",
+            "jz {handler}",
+            "swapgs",
+            "jmp {handler}",
+            error_code = const $error_code,
+            handler = sym $handler,
+        );
+        extern "C" {
+            fn $entry();
+        }
+    };
+}
+
+swapgs_entry!(fault divide_error_entry, handlers::divide_error, 0);
+swapgs_entry!(fault double_fault_entry, handlers::double_fault, 8);
+swapgs_entry!(fault general_protection_fault_entry, handlers::general_protection_fault, 8);
+swapgs_entry!(fault page_fault_entry, handlers::page_fault, 8);
+swapgs_entry!(timer_entry, handlers::timer_interrupt);
+swapgs_entry!(keyboard_entry, handlers::keyboard_interrupt);
+swapgs_entry!(com1_entry, handlers::com1_interrupt);
+swapgs_entry!(primary_ata_entry, handlers::primary_ata_interrupt);
+swapgs_entry!(secondary_ata_entry, handlers::secondary_ata_interrupt);
+swapgs_entry!(lapic_timer_entry, handlers::lapic_timer_interrupt);
+swapgs_entry!(spurious_entry, handlers::spurious_interrupt);
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     DivideError,
+    Debug,
+    NonMaskable,
+    Breakpoint,
     DoubleFault = 8,
     GeneralProtectionFault = 13,
     PageFault = 14,
     Timer = PIC_OFFSET,
     Keyboard,
+    Com2 = PIC_OFFSET + 3,
+    Com1,
+    PrimaryAta = PIC_OFFSET + 14,
+    SecondaryAta,
+    Syscall = 0x80,
+    LapicTimer = 0xF0,
+    Spurious = 0xFF,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -37,12 +118,20 @@ impl IdtEntry {
         }
     }
 
+    // for handlers that don't touch per-CPU data, so they can skip swapgs_entry
     fn set_handler(&mut self, handler: extern "x86-interrupt" fn()) -> &mut Self {
-        let address = handler as u64;
+        self.set_handler_address(handler as u64)
+    }
+
+    fn set_entry(&mut self, entry: unsafe extern "C" fn()) -> &mut Self {
+        self.set_handler_address(entry as u64)
+    }
+
+    fn set_handler_address(&mut self, address: u64) -> &mut Self {
         self.fn_pointer_low = address as u16;
         self.fn_pointer_middle = (address >> 16) as u16;
         self.fn_pointer_high = (address >> 32) as u32;
-        self.cs = GDT.selectors.code;
+        self.cs = KERNEL_CODE_SELECTOR;
         self.flags |= 0b10000000;
         self
     }
@@ -50,6 +139,11 @@ impl IdtEntry {
     fn with_ist_index(&mut self, index: usize) {
         self.ist = index as u8;
     }
+
+    // lets ring 3 use `int` on it
+    fn with_user_access(&mut self) {
+        self.flags |= 3 << 5;
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -93,12 +187,24 @@ pub fn init() {
     lazy_static! {
         static ref IDT: Idt = {
             let mut idt = Idt::new();
-            idt[InterruptIndex::Timer].set_handler(handlers::timer_interrupt);
-            idt[InterruptIndex::DoubleFault].set_handler(handlers::double_fault).with_ist_index(DOUBLE_FAULT_IST_INDEX);
-            idt[InterruptIndex::Keyboard].set_handler(handlers::keyboard_interrupt);
-            idt[InterruptIndex::DivideError].set_handler(handlers::divide_error);
-            idt[InterruptIndex::GeneralProtectionFault].set_handler(handlers::general_protection_fault);
-            idt[InterruptIndex::PageFault].set_handler(handlers::page_fault);
+            idt[InterruptIndex::Timer].set_entry(timer_entry);
+            idt[InterruptIndex::DoubleFault].set_entry(double_fault_entry).with_ist_index(DOUBLE_FAULT_IST_INDEX);
+            idt[InterruptIndex::Keyboard].set_entry(keyboard_entry);
+            // an NMI can land between a swapgs and the return to ring 3, where cs
+            // doesn't tell which GS base is in, so its handler stays off per-CPU data
+            idt[InterruptIndex::NonMaskable].set_handler(handlers::non_maskable_interrupt);
+            idt[InterruptIndex::DivideError].set_entry(divide_error_entry);
+            idt[InterruptIndex::GeneralProtectionFault].set_entry(general_protection_fault_entry);
+            idt[InterruptIndex::PageFault].set_entry(page_fault_entry);
+            idt[InterruptIndex::Com1].set_entry(com1_entry);
+            idt[InterruptIndex::Debug].set_entry(gdb_debug_entry);
+            idt[InterruptIndex::Breakpoint].set_entry(gdb_breakpoint_entry);
+            idt[InterruptIndex::Com2].set_entry(gdb_com2_entry);
+            idt[InterruptIndex::PrimaryAta].set_entry(primary_ata_entry);
+            idt[InterruptIndex::SecondaryAta].set_entry(secondary_ata_entry);
+            idt[InterruptIndex::LapicTimer].set_entry(lapic_timer_entry);
+            idt[InterruptIndex::Spurious].set_entry(spurious_entry);
+            idt[InterruptIndex::Syscall].set_entry(syscall_interrupt).with_user_access();
             idt
         };
     }
@@ -0,0 +1,95 @@
+use super::pit;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+const ID: usize = 0x20;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const ERROR_STATUS: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const SPURIOUS_VECTOR: u32 = super::idt::InterruptIndex::Spurious as u32;
+const TIMER_VECTOR: u32 = super::idt::InterruptIndex::LapicTimer as u32;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+const CALIBRATION_TICKS: u32 = 5;
+
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static COUNT_PER_TICK: AtomicU32 = AtomicU32::new(0);
+
+fn read(register: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((BASE.load(Ordering::Relaxed) + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((BASE.load(Ordering::Relaxed) + register) as *mut u32, value) }
+}
+
+pub fn init(base: usize) {
+    crate::memory::map_mmio(base, 0x1000);
+    BASE.store(base, Ordering::Relaxed);
+    enable();
+}
+
+/// Software enables the local apic of the calling CPU.
+pub fn enable() {
+    write(SPURIOUS, SPURIOUS_VECTOR | 1 << 8);
+}
+
+pub fn eoi() {
+    write(EOI, 0);
+}
+
+/// Measures the timer against the PIT, needs PIT ticks coming in.
+pub fn calibrate_timer() {
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    // start right on a tick
+    let start = pit::ticks();
+    while pit::ticks() == start {}
+
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    let start = pit::ticks();
+    while pit::ticks().wrapping_sub(start) < CALIBRATION_TICKS {}
+    let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+
+    COUNT_PER_TICK.store((elapsed / CALIBRATION_TICKS).max(1), Ordering::SeqCst);
+}
+
+/// Interrupts the calling CPU once per PIT tick. The PIT only reaches the
+/// BSP, so this is what wakes the other CPUs out of `hlt` to check the time.
+pub fn start_timer() {
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_VECTOR | TIMER_PERIODIC);
+    write(TIMER_INITIAL_COUNT, COUNT_PER_TICK.load(Ordering::SeqCst));
+}
+
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    write(ERROR_STATUS, 0);
+    write(ICR_HIGH, (apic_id as u32) << 24);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & DELIVERY_PENDING != 0 {}
+}
+
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, 0x4500); // init, level assert
+}
+
+/// The CPU starts executing in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, 0x4600 | page as u32);
+}
+
+/// Non-maskable interrupt to every CPU but the calling one.
+pub fn send_nmi_to_others() {
+    send_ipi(0, 0xC_0400); // all excluding self, nmi delivery
+}
@@ -5,6 +5,7 @@ pub mod gdt;
 pub mod pic;
 pub mod pit;
 pub mod idt;
+pub mod lapic;
 pub mod handlers;
 pub mod norwegian;
 
@@ -22,11 +23,41 @@ pub struct TablePointer {
 }
 
 pub fn init() {
-    gdt::init();
     pic::init();
     pit::init();
     idt::init();
 
-    // enable interrupts
+    enable();
+}
+
+pub fn enable() {
     unsafe { asm!("sti", options(preserves_flags, nostack)); }
-}
\ No newline at end of file
+}
+
+pub fn disable() {
+    unsafe { asm!("cli", options(preserves_flags, nostack)); }
+}
+
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)); }
+    rflags & (1 << 9) != 0 // IF bit
+}
+
+/// Enables interrupts and halts until the next one arrives. The `sti` shadow
+/// makes sure an interrupt can't slip in between the two instructions.
+pub fn enable_and_hlt() {
+    unsafe { asm!("sti", "hlt", options(nomem, nostack)); }
+}
+
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+    let result = f();
+    if enabled {
+        enable();
+    }
+    result
+}
@@ -38,6 +38,18 @@ impl Pics {
         }
         outb(self.primary.command_port, 0x20); // primary
     }
+
+    /// Lets an irq line (0 to 15) through. Lines on the secondary also need the cascade on line 2.
+    pub fn unmask(&mut self, irq: u8) {
+        let (pic, line) = match irq {
+            0..=7 => (&self.primary, irq),
+            _ => {
+                self.unmask(2);
+                (&self.secondary, irq - 8)
+            },
+        };
+        outb(pic.data_port, inb(pic.data_port) & !(1 << line));
+    }
 }
 
 pub fn init() {
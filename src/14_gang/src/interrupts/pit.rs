@@ -1,3 +1,4 @@
+use spin::Mutex;
 use crate::util::outb;
 use super::SYSTEM_TICKS;
 use core::sync::atomic::Ordering;
@@ -5,6 +6,11 @@ use core::sync::atomic::Ordering;
 pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
 pub const TIMER_FREQUENCY: u32 = 100; // 10 ms per tick
 
+const MAX_CALLBACKS: usize = 8;
+
+// (period in ticks, callback), run from the timer interrupt
+static CALLBACKS: Mutex<[Option<(u32, fn())>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+
 pub fn init() {
     let divisor = PIT_BASE_FREQUENCY / TIMER_FREQUENCY;
 
@@ -13,8 +19,46 @@ pub fn init() {
     outb(0x40, ((divisor >> 8) & 0xFF) as u8);
 }
 
+pub fn ticks() -> u32 {
+    SYSTEM_TICKS.load(Ordering::SeqCst)
+}
+
+/// Saturates at `u32::MAX` ticks, a bit over a year.
+pub const fn ms_to_ticks(milliseconds: u32) -> u32 {
+    let ticks = milliseconds as u64 * TIMER_FREQUENCY as u64 / 1000;
+    if ticks > u32::MAX as u64 { u32::MAX } else { ticks as u32 }
+}
+
 pub fn sleep_busy(milliseconds: u32) {
-    let ticks = milliseconds * TIMER_FREQUENCY / 1000;
-    let target = SYSTEM_TICKS.load(Ordering::SeqCst) + ticks;
-    while SYSTEM_TICKS.load(Ordering::SeqCst) < target {}
-}
\ No newline at end of file
+    let target = ticks().saturating_add(ms_to_ticks(milliseconds));
+    while ticks() < target {}
+}
+
+/// Like `sleep_busy`, but halts between ticks instead of spinning. Works on
+/// every CPU, the others get woken by their local apic timer.
+pub fn sleep(milliseconds: u32) {
+    let target = ticks().saturating_add(ms_to_ticks(milliseconds));
+    while ticks() < target {
+        super::enable_and_hlt();
+    }
+}
+
+/// Calls `callback` from the timer interrupt every `milliseconds`. It runs in
+/// interrupt context, so it should only set flags or wake something up.
+pub fn every(milliseconds: u32, callback: fn()) {
+    let period = ms_to_ticks(milliseconds).max(1);
+    let mut callbacks = CALLBACKS.lock();
+    let slot = callbacks.iter_mut().find(|slot| slot.is_none()).expect("Too many timer callbacks");
+    *slot = Some((period, callback));
+}
+
+pub fn run_callbacks(ticks: u32) {
+    // a callback being registered right now can wait for the next tick
+    if let Some(callbacks) = CALLBACKS.try_lock() {
+        for &(period, callback) in callbacks.iter().flatten() {
+            if ticks % period == 0 {
+                callback();
+            }
+        }
+    }
+}
@@ -1,30 +1,67 @@
 #![no_std]
+#![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::run)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate rlibc;
 extern crate alloc;
 
 #[macro_use]
-mod vga;
+pub mod vga;
+mod cpu;
+mod smp;
+mod acpi;
 mod util;
 mod music;
+mod panic;
 mod memory;
 mod interrupts;
+pub mod backtrace;
+pub mod gdb;
+pub mod block;
+pub mod elf;
+pub mod fs;
+pub mod initrd;
+pub mod logger;
+pub mod process;
+#[macro_use]
+pub mod serial;
+pub mod sync;
+pub mod syscall;
+pub mod testing;
+pub mod usermode;
+
+use multiboot2::{BootInformation, BootInformationHeader};
 
 #[no_mangle]
 pub extern fn rust_main(multiboot_addr: usize) {
+    let boot_info = unsafe { BootInformation::load(multiboot_addr as *const BootInformationHeader).unwrap() };
+
+    serial::init();
+    logger::init(log::LevelFilter::Info);
+    backtrace::init(&boot_info);
     vga::clear_screen();
     util::init();    
-    memory::init(multiboot_addr);
+    memory::init(&boot_info);
+    initrd::init(&boot_info);
+    fs::init();
+    cpu::init(0);
     interrupts::init();
+    gdb::init();
+    syscall::init();
+    block::init();
+    fs::mount_devices();
+    smp::init(&boot_info);
+
+    #[cfg(test)]
+    test_main();
 
     music::play_songs();
     println!("Loop reached");
-    util::hlt_loop()
+    loop {
+        block::cache::write_back_if_due();
+        interrupts::enable_and_hlt();
+    }
 }
-
-use core::panic::PanicInfo;
-#[panic_handler]
-fn panic(_: &PanicInfo) -> ! { 
-    util::hlt_loop()
-}
\ No newline at end of file
@@ -0,0 +1,153 @@
+//! Kernel log on top of the `log` crate
+//!
+//! Records get a timestamp from the PIT and the module they came from, and go
+//! to the enabled sinks plus a ring buffer that can be dumped later (dmesg).
+use core::fmt::{self, Write};
+use spin::Mutex;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::interrupts::{self, pit};
+
+// sinks, besides the ring buffer which always gets everything that passes the filter
+pub const VGA: u8 = 1 << 0;
+pub const SERIAL: u8 = 1 << 1;
+
+const DMESG_SIZE: usize = 16 * 1024;
+const LINE_SIZE: usize = 256;
+
+static LOGGER: KernelLogger = KernelLogger;
+static SINKS: AtomicU8 = AtomicU8::new(VGA | SERIAL);
+static DMESG: Mutex<Dmesg> = Mutex::new(Dmesg { bytes: [0; DMESG_SIZE], end: 0, wrapped: false });
+
+struct KernelLogger;
+
+// the newest DMESG_SIZE bytes of the log
+struct Dmesg {
+    bytes: [u8; DMESG_SIZE],
+    end: usize,
+    wrapped: bool,
+}
+
+impl Write for Dmesg {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.bytes[self.end] = byte;
+            self.end = (self.end + 1) % DMESG_SIZE;
+            self.wrapped |= self.end == 0;
+        }
+        Ok(())
+    }
+}
+
+impl Dmesg {
+    // the oldest part first
+    fn parts(&self) -> [&[u8]; 2] {
+        match self.wrapped {
+            true => [&self.bytes[self.end..], &self.bytes[..self.end]],
+            false => [&self.bytes[..self.end], &[]],
+        }
+    }
+}
+
+// A record is formatted here first, so every sink gets it in a single locked
+// write and lines from different CPUs don't interleave. Longer lines are cut.
+struct Line {
+    bytes: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut take = s.len().min(LINE_SIZE - 1 - self.len); // room for the newline
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.bytes[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let ticks = pit::ticks();
+        let (seconds, hundredths) = (ticks / pit::TIMER_FREQUENCY, ticks % pit::TIMER_FREQUENCY * 100 / pit::TIMER_FREQUENCY);
+        let module = record.module_path().unwrap_or(record.target()).trim_start_matches("os::");
+
+        let mut line = Line { bytes: [0; LINE_SIZE], len: 0 };
+        let _ = write!(line, "[{:>5}.{:02}] {} {}: {}", seconds, hundredths, label(record.level()), module, record.args());
+        line.bytes[line.len] = b'\n';
+        line.len += 1;
+
+        let sinks = SINKS.load(Ordering::Relaxed);
+        interrupts::without_interrupts(|| {
+            if sinks & VGA != 0 {
+                let _ = crate::vga::WRITER.lock().write_str(line.as_str());
+            }
+            if sinks & SERIAL != 0 {
+                let _ = crate::serial::SERIAL1.lock().write_str(line.as_str());
+            }
+            let _ = DMESG.lock().write_str(line.as_str());
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn label(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).expect("Logger set twice");
+    log::set_max_level(level);
+}
+
+/// Changes which records get through, at runtime.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn set_sinks(sinks: u8) {
+    SINKS.store(sinks, Ordering::Relaxed);
+}
+
+/// Frees the ring buffer for `dump` after a panic.
+///
+/// # Safety
+/// Whoever held the lock must never touch the buffer again, like a CPU halted by the panic.
+pub unsafe fn force_unlock() {
+    DMESG.force_unlock();
+}
+
+/// Writes out everything still in the ring buffer, oldest first.
+pub fn dump(out: &mut impl Write) -> fmt::Result {
+    let dmesg = DMESG.lock();
+    // no allocating here, this is meant to work after a crash too
+    for chunk in dmesg.parts().iter().flat_map(|part| part.utf8_chunks()) {
+        out.write_str(chunk.valid())?;
+        if !chunk.invalid().is_empty() {
+            out.write_char('?')?;
+        }
+    }
+    Ok(())
+}
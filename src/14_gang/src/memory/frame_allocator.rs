@@ -0,0 +1,76 @@
+use crate::util::align_up;
+use multiboot2::{MemoryArea, MemoryAreaType};
+
+pub const FRAME_SIZE: usize = 4096;
+const MAX_AREAS: usize = 16;
+// frames are accessed through the boot identity map, which covers the first GiB
+const IDENTITY_MAPPED_END: usize = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame(usize);
+
+impl Frame {
+    pub fn containing(address: usize) -> Frame {
+        Frame(address / FRAME_SIZE)
+    }
+
+    pub fn start_address(&self) -> usize {
+        self.0 * FRAME_SIZE
+    }
+}
+
+/// Hands out frames from the available memory areas, above everything that was
+/// in use at boot. Freed frames are kept in a list threaded through the frames themselves.
+pub struct FrameAllocator {
+    areas: [(usize, usize); MAX_AREAS],
+    area_count: usize,
+    next: usize,
+    free_list: usize, // address of the first free frame, 0 when empty
+}
+
+impl FrameAllocator {
+    pub const fn new() -> Self {
+        FrameAllocator {
+            areas: [(0, 0); MAX_AREAS],
+            area_count: 0,
+            next: 0,
+            free_list: 0,
+        }
+    }
+
+    pub fn init(&mut self, areas: &[MemoryArea], reserved_end: usize) {
+        let available = areas.iter()
+            .filter(|area| MemoryAreaType::from(area.typ()) == MemoryAreaType::Available)
+            .map(|area| (area.start_address() as usize, (area.end_address() as usize).min(IDENTITY_MAPPED_END)))
+            .filter(|&(start, end)| start < end)
+            .take(MAX_AREAS);
+
+        for area in available {
+            self.areas[self.area_count] = area;
+            self.area_count += 1;
+        }
+        self.next = reserved_end;
+    }
+
+    pub fn allocate(&mut self) -> Option<Frame> {
+        if self.free_list != 0 {
+            let frame = Frame::containing(self.free_list);
+            self.free_list = unsafe { *(self.free_list as *const usize) };
+            return Some(frame);
+        }
+        for &(start, end) in &self.areas[..self.area_count] {
+            let address = align_up(start.max(self.next), FRAME_SIZE);
+            if address + FRAME_SIZE <= end {
+                self.next = address + FRAME_SIZE;
+                return Some(Frame::containing(address));
+            }
+        }
+        None
+    }
+
+    pub fn deallocate(&mut self, frame: Frame) {
+        let address = frame.start_address();
+        unsafe { *(address as *mut usize) = self.free_list };
+        self.free_list = address;
+    }
+}
@@ -1,18 +1,56 @@
 use spin::Mutex;
+use multiboot2::BootInformation;
+use frame_allocator::FrameAllocator;
 use heap_allocator::{HeapAllocator, LockedHeap};
-use multiboot2::{BootInformation, BootInformationHeader};
 
+pub use frame_allocator::{Frame, FRAME_SIZE};
+
+pub mod paging;
 mod heap_allocator;
+mod frame_allocator;
 
 #[global_allocator]
 pub static HEAP_ALLOCATOR: LockedHeap = LockedHeap(Mutex::new(HeapAllocator::new()));
+static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
 
-pub fn init(multiboot_addr: usize) {
-    let kernel_end = unsafe {
-        BootInformation::load(multiboot_addr as *const BootInformationHeader).unwrap().end_address()
-    };
+pub fn init(boot_info: &BootInformation) {
+    // GRUB puts boot modules and the symbol table after the kernel, the heap goes past all of them
+    let kernel_end = boot_info.end_address()
+        .max(crate::initrd::modules_end(boot_info))
+        .max(crate::backtrace::symbols_end(boot_info));
 
     let heap_start = crate::util::align_up(kernel_end, 2000 * 1024);
     let heap_size = 1000 * 1024; // 1MiB
-    HEAP_ALLOCATOR.lock().init(heap_start, heap_size);
-}
\ No newline at end of file
+    // nothing else uses the memory past the kernel and modules
+    unsafe { HEAP_ALLOCATOR.lock().init(heap_start, heap_size) };
+
+    let memory_map = boot_info.memory_map_tag().expect("Memory map tag required");
+    FRAME_ALLOCATOR.lock().init(memory_map.memory_areas(), heap_start + heap_size);
+}
+
+pub fn allocate_frame() -> Option<Frame> {
+    FRAME_ALLOCATOR.lock().allocate()
+}
+
+pub fn deallocate_frame(frame: Frame) {
+    FRAME_ALLOCATOR.lock().deallocate(frame)
+}
+
+pub fn allocate_zeroed_frame() -> Option<Frame> {
+    let frame = allocate_frame()?;
+    unsafe { core::ptr::write_bytes(frame.start_address() as *mut u8, 0, FRAME_SIZE) };
+    Some(frame)
+}
+
+/// Identity maps device memory with caching off, skipping pages that are mapped already.
+pub fn map_mmio(address: usize, size: usize) {
+    let p4 = paging::active_p4();
+    let start = address & !(paging::PAGE_SIZE - 1);
+    for page in (start..address + size).step_by(paging::PAGE_SIZE) {
+        if paging::translate(p4, page).is_none() {
+            let flags = paging::WRITABLE | paging::NO_CACHE | paging::WRITE_THROUGH | paging::NO_EXECUTE;
+            paging::map_to(p4, page, Frame::containing(page), flags).expect("Failed to map mmio");
+            paging::flush(page);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use core::arch::asm;
+use crate::usermode::{USER_START, USER_END};
+use super::frame_allocator::{Frame, FRAME_SIZE};
+
+pub const PAGE_SIZE: usize = FRAME_SIZE;
+const ENTRY_COUNT: usize = 512;
+
+pub const PRESENT: u64 = 1 << 0;
+pub const WRITABLE: u64 = 1 << 1;
+pub const USER: u64 = 1 << 2;
+pub const WRITE_THROUGH: u64 = 1 << 3;
+pub const NO_CACHE: u64 = 1 << 4;
+pub const HUGE: u64 = 1 << 7;
+pub const NO_EXECUTE: u64 = 1 << 63;
+
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[derive(Debug)]
+pub enum MapError {
+    FrameAllocationFailed,
+    AlreadyMapped,
+    NotMapped,
+    InsideHugePage,
+}
+
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Entry(u64);
+
+impl Entry {
+    pub fn is_present(&self) -> bool {
+        self.0 & PRESENT != 0
+    }
+
+    pub fn flags(&self) -> u64 {
+        self.0 & !ADDRESS_MASK
+    }
+
+    pub fn address(&self) -> usize {
+        (self.0 & ADDRESS_MASK) as usize
+    }
+
+    pub fn set(&mut self, address: usize, flags: u64) {
+        self.0 = (address as u64 & ADDRESS_MASK) | flags;
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [Entry; ENTRY_COUNT],
+}
+
+impl PageTable {
+    /// Page tables live in frames below 1GiB, so their physical address is also their virtual one.
+    pub unsafe fn at(address: usize) -> &'static mut PageTable {
+        &mut *(address as *mut PageTable)
+    }
+
+    fn next_table_create(&mut self, index: usize, flags: u64) -> Result<&'static mut PageTable, MapError> {
+        let entry = &mut self.entries[index];
+        if !entry.is_present() {
+            let frame = super::allocate_zeroed_frame().ok_or(MapError::FrameAllocationFailed)?;
+            entry.set(frame.start_address(), PRESENT | WRITABLE);
+        }
+        if entry.flags() & HUGE != 0 {
+            return Err(MapError::InsideHugePage);
+        }
+        // user pages need the user bit on every level above them
+        entry.set(entry.address(), entry.flags() | (flags & USER));
+        Ok(unsafe { PageTable::at(entry.address()) })
+    }
+
+    fn next_table(&self, index: usize) -> Option<&'static mut PageTable> {
+        let entry = &self.entries[index];
+        (entry.is_present() && entry.flags() & HUGE == 0).then(|| unsafe { PageTable::at(entry.address()) })
+    }
+}
+
+fn indices(page: usize) -> [usize; 4] {
+    [39, 30, 21, 12].map(|shift| (page >> shift) & (ENTRY_COUNT - 1))
+}
+
+pub fn active_p4() -> usize {
+    let cr3: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)); }
+    cr3 & ADDRESS_MASK as usize
+}
+
+pub fn switch(p4: usize) {
+    unsafe { asm!("mov cr3, {}", in(reg) p4, options(nostack, preserves_flags)); }
+}
+
+/// A fresh P4 that shares everything outside user space with the active one.
+pub fn new_address_space() -> Result<usize, MapError> {
+    let frame = super::allocate_zeroed_frame().ok_or(MapError::FrameAllocationFailed)?;
+    let (active, new) = unsafe { (PageTable::at(active_p4()), PageTable::at(frame.start_address())) };
+
+    let user = indices(USER_START)[0]..indices(USER_END)[0];
+    for index in (0..ENTRY_COUNT).filter(|index| !user.contains(index)) {
+        new.entries[index] = active.entries[index];
+    }
+    new.entries[ENTRY_COUNT - 1].set(frame.start_address(), PRESENT | WRITABLE); // recursive, like boot.asm
+    Ok(frame.start_address())
+}
+
+/// Frees every frame mapped in the user half of `p4`, the tables holding them and the P4 itself.
+/// The address space must not be active.
+pub fn free_address_space(p4: usize) {
+    assert_ne!(p4, active_p4(), "Freeing the active address space");
+    let table = unsafe { PageTable::at(p4) };
+    for index in indices(USER_START)[0]..indices(USER_END)[0] {
+        free_table(table.entries[index], 3);
+    }
+    super::deallocate_frame(Frame::containing(p4));
+}
+
+// level 3 is a P3, level 0 a mapped frame
+fn free_table(entry: Entry, level: usize) {
+    if !entry.is_present() {
+        return;
+    }
+    if level > 0 && entry.flags() & HUGE == 0 {
+        let table = unsafe { PageTable::at(entry.address()) };
+        for &entry in table.entries.iter() {
+            free_table(entry, level - 1);
+        }
+    }
+    super::deallocate_frame(Frame::containing(entry.address()));
+}
+
+pub fn flush(page: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) page, options(nostack, preserves_flags)); }
+}
+
+pub fn map_to(p4: usize, page: usize, frame: Frame, flags: u64) -> Result<(), MapError> {
+    let [p4_index, p3_index, p2_index, p1_index] = indices(page);
+    let p4 = unsafe { PageTable::at(p4) };
+    let p1 = p4.next_table_create(p4_index, flags)?
+        .next_table_create(p3_index, flags)?
+        .next_table_create(p2_index, flags)?;
+
+    let entry = &mut p1.entries[p1_index];
+    if entry.is_present() {
+        return Err(MapError::AlreadyMapped);
+    }
+    entry.set(frame.start_address(), flags | PRESENT);
+    Ok(())
+}
+
+pub fn set_flags(p4: usize, page: usize, flags: u64) -> Result<(), MapError> {
+    let entry = p1_entry(p4, page).filter(|entry| entry.is_present()).ok_or(MapError::NotMapped)?;
+    entry.set(entry.address(), flags | PRESENT);
+    flush(page);
+    Ok(())
+}
+
+pub fn flags(p4: usize, page: usize) -> Option<u64> {
+    p1_entry(p4, page).filter(|entry| entry.is_present()).map(|entry| entry.flags())
+}
+
+fn p1_entry(p4: usize, page: usize) -> Option<&'static mut Entry> {
+    let [p4_index, p3_index, p2_index, p1_index] = indices(page);
+    let p1 = unsafe { PageTable::at(p4) }
+        .next_table(p4_index)?
+        .next_table(p3_index)?
+        .next_table(p2_index)?;
+    Some(&mut p1.entries[p1_index])
+}
+
+pub fn translate(p4: usize, address: usize) -> Option<usize> {
+    let [p4_index, p3_index, p2_index, p1_index] = indices(address);
+    let p3 = unsafe { PageTable::at(p4) }.next_table(p4_index)?;
+
+    let p3_entry = p3.entries[p3_index];
+    if p3_entry.is_present() && p3_entry.flags() & HUGE != 0 {
+        return Some(p3_entry.address() + (address & 0x3fff_ffff));
+    }
+    let p2 = p3.next_table(p3_index)?;
+
+    let p2_entry = p2.entries[p2_index];
+    if p2_entry.is_present() && p2_entry.flags() & HUGE != 0 {
+        return Some(p2_entry.address() + (address & 0x1f_ffff));
+    }
+    let p1_entry = p2.next_table(p2_index)?.entries[p1_index];
+    p1_entry.is_present().then(|| p1_entry.address() + (address & (PAGE_SIZE - 1)))
+}
+
+/// Whether writing to `address` would go through, which takes the writable bit on every level.
+pub fn is_writable(p4: usize, address: usize) -> bool {
+    let mut table = unsafe { PageTable::at(p4) };
+    for (level, index) in indices(address).into_iter().enumerate() {
+        let entry = table.entries[index];
+        if !entry.is_present() || entry.flags() & WRITABLE == 0 {
+            return false;
+        }
+        if level == 3 || entry.flags() & HUGE != 0 {
+            return true;
+        }
+        table = unsafe { PageTable::at(entry.address()) };
+    }
+    false
+}
+
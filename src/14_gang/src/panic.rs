@@ -0,0 +1,121 @@
+//! What happens when the kernel panics
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::vga::{Color, WRITER};
+use crate::serial::SERIAL1;
+use crate::interrupts::{self, lapic};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// the whole report goes to both screen and serial
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        WRITER.lock().write_str(s)?;
+        SERIAL1.lock().write_str(s)
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let registers = capture_registers();
+    interrupts::disable();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        halt(); // another CPU is already reporting, or we panicked while reporting
+    }
+    stop_other_cpus();
+
+    // whoever held these isn't coming back to release them
+    unsafe {
+        WRITER.force_unlock();
+        SERIAL1.force_unlock();
+        crate::logger::force_unlock();
+    }
+    WRITER.lock().set_color(Color::LightRed, Color::Black);
+
+    // finishes the line of the test that was running
+    #[cfg(test)]
+    let _ = write!(Console, "FAILED");
+    // the log first, so the report itself stays on screen
+    let _ = writeln!(Console, "\nLog:");
+    let _ = crate::logger::dump(&mut Console);
+    let cpu = crate::cpu::current_id();
+    let _ = writeln!(Console, "\nKERNEL PANIC on CPU {}: {}", cpu, info.message());
+    if let Some(location) = info.location() {
+        let _ = writeln!(Console, "  at {}:{}:{}", location.file(), location.line(), location.column());
+    }
+    let _ = dump_registers(&mut Console, &registers);
+    let _ = writeln!(Console, "Backtrace:");
+    crate::backtrace::trace(|address| {
+        let _ = writeln!(Console, "  {}", crate::backtrace::Symbolized(address));
+    });
+
+    #[cfg(test)]
+    crate::testing::exit_qemu(crate::testing::ExitCode::Failed);
+    #[cfg(not(test))]
+    halt()
+}
+
+const GPR_NAMES: [&str; 15] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "rbp",
+];
+
+// The general purpose registers as the panic handler found them. Only callee
+// saved ones (rbx, rbp, r12 to r15) still say much about the code that panicked,
+// the rest went to the panic machinery. The register holding the pointer to
+// the array shows the array's address.
+#[inline(always)]
+fn capture_registers() -> [u64; 15] {
+    let mut registers = [0u64; 15];
+    unsafe {
+        asm!(
+            "mov [{0}], rax", "mov [{0} + 8], rbx", "mov [{0} + 16], rcx", "mov [{0} + 24], rdx",
+            "mov [{0} + 32], rsi", "mov [{0} + 40], rdi", "mov [{0} + 48], r8", "mov [{0} + 56], r9",
+            "mov [{0} + 64], r10", "mov [{0} + 72], r11", "mov [{0} + 80], r12", "mov [{0} + 88], r13",
+            "mov [{0} + 96], r14", "mov [{0} + 104], r15", "mov [{0} + 112], rbp",
+            in(reg) registers.as_mut_ptr(), options(nostack, preserves_flags),
+        );
+    }
+    registers
+}
+
+fn dump_registers(out: &mut impl Write, registers: &[u64; 15]) -> fmt::Result {
+    for (names, values) in GPR_NAMES.chunks(4).zip(registers.chunks(4)) {
+        for (name, value) in names.iter().zip(values) {
+            write!(out, "  {:>3} {:#018x}", name, value)?;
+        }
+        writeln!(out)?;
+    }
+    let (rsp, rflags): (u64, u64);
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+        asm!("mov {}, cr0", "mov {}, cr2", out(reg) cr0, out(reg) cr2, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr3", "mov {}, cr4", out(reg) cr3, out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    writeln!(out, "  rsp {:#018x}  rflags {:#018x}", rsp, rflags)?;
+    writeln!(out, "  cr0 {:#018x}  cr2 {:#018x}  cr3    {:#018x}  cr4 {:#x}", cr0, cr2, cr3, cr4)
+}
+
+// sends an NMI to every other CPU, their handler halts when it sees the panic
+fn stop_other_cpus() {
+    if crate::smp::CPU_COUNT.load(Ordering::SeqCst) > 1 {
+        lapic::send_nmi_to_others();
+    }
+}
+
+/// True once some CPU has panicked.
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+pub fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)); }
+    }
+}
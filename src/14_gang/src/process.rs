@@ -0,0 +1,204 @@
+//! Processes, each with its own user address space
+//!
+//! There is no scheduler, so a process runs to completion on the CPU that waits
+//! for it. Spawning only loads it; `wait` is what runs it.
+use spin::Mutex;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::elf;
+use crate::fs::{self, File};
+use crate::memory::paging;
+
+pub type Pid = usize;
+
+/// Pid of the kernel itself, the parent of everything spawned outside a process.
+pub const KERNEL_PID: Pid = 0;
+/// Exit status of a killed process.
+pub const KILLED: i64 = -9;
+
+#[derive(Debug)]
+pub enum Error {
+    Elf(elf::Error),
+    Fs(fs::Error), // no console to give it, /dev isn't mounted
+}
+
+impl From<elf::Error> for Error {
+    fn from(error: elf::Error) -> Self {
+        Error::Elf(error)
+    }
+}
+
+impl From<fs::Error> for Error {
+    fn from(error: fs::Error) -> Self {
+        Error::Fs(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready { entry: u64, stack: u64 },
+    Running,
+    Exited(i64),
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub p4: usize,
+    pub state: State,
+    pub children: Vec<Pid>,
+    pub fds: Vec<Option<Arc<File>>>,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+/// The process running on this CPU, or `KERNEL_PID`.
+pub fn current() -> Pid {
+    crate::cpu::current().process.load(Ordering::Relaxed)
+}
+
+/// Loads an executable as a child of the current process. It starts running once waited for.
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, Error> {
+    // before the address space exists, so there is nothing to free if it fails
+    let console = fs::open("/dev/console", fs::READ | fs::WRITE)?;
+    let program = elf::load(image)?;
+    let stack = match program.setup_stack(argv, envp) {
+        Ok(stack) => stack,
+        Err(error) => {
+            paging::free_address_space(program.p4);
+            return Err(error.into());
+        },
+    };
+
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let parent = current();
+    let process = Process {
+        pid,
+        parent,
+        p4: program.p4,
+        state: State::Ready { entry: program.entry, stack },
+        children: Vec::new(),
+        fds: vec![Some(console.clone()), Some(console.clone()), Some(console)], // stdin, stdout and stderr
+    };
+
+    let mut processes = PROCESSES.lock();
+    if let Some(parent) = processes.get_mut(&parent) {
+        parent.children.push(pid);
+    }
+    processes.insert(pid, process);
+    Ok(pid)
+}
+
+/// Runs the child `pid` if it hasn't yet, then reaps it and returns its exit status.
+/// Returns `None` if `pid` isn't a child of the current process.
+pub fn wait(pid: Pid) -> Option<i64> {
+    let state = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).filter(|process| process.parent == current())?;
+        let state = process.state;
+        if let State::Ready { .. } = state {
+            process.state = State::Running;
+        }
+        state
+    };
+
+    let status = match state {
+        State::Ready { entry, stack } => run(pid, entry, stack),
+        State::Exited(status) => status,
+        State::Running => return None, // waited for twice at once
+    };
+    reap(pid);
+    Some(status)
+}
+
+/// Kills a process that isn't running. The current process can kill itself,
+/// anything else that is running on some CPU can't be stopped, and neither can the kernel.
+pub fn kill(pid: Pid) -> bool {
+    if pid == KERNEL_PID {
+        return false;
+    }
+    if pid == current() {
+        crate::usermode::exit(KILLED);
+    }
+    let mut processes = PROCESSES.lock();
+    match processes.get_mut(&pid) {
+        Some(process) if matches!(process.state, State::Ready { .. }) => {
+            process.state = State::Exited(KILLED);
+            paging::free_address_space(process.p4);
+            true
+        },
+        _ => false,
+    }
+}
+
+impl Process {
+    /// Puts the file in the lowest free descriptor and returns it.
+    pub fn add_file(&mut self, file: Arc<File>) -> usize {
+        match self.fds.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.fds[fd] = Some(file);
+                fd
+            },
+            None => {
+                self.fds.push(Some(file));
+                self.fds.len() - 1
+            },
+        }
+    }
+
+    pub fn file(&self, fd: usize) -> Option<Arc<File>> {
+        self.fds.get(fd).cloned().flatten()
+    }
+
+    pub fn close(&mut self, fd: usize) -> bool {
+        self.fds.get_mut(fd).and_then(Option::take).is_some()
+    }
+}
+
+/// Calls `f` with the current process, if there is one.
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    PROCESSES.lock().get_mut(&current()).map(f)
+}
+
+fn run(pid: Pid, entry: u64, stack: u64) -> i64 {
+    let p4 = PROCESSES.lock()[&pid].p4;
+    let cpu = crate::cpu::current();
+    let parent_p4 = paging::active_p4();
+    let parent = cpu.process.swap(pid, Ordering::Relaxed);
+
+    paging::switch(p4);
+    let status = unsafe { crate::usermode::run(entry, stack) };
+    paging::switch(parent_p4);
+    cpu.process.store(parent, Ordering::Relaxed);
+
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid).unwrap();
+    process.state = State::Exited(status);
+    paging::free_address_space(process.p4);
+
+    // children never waited for won't run now, nothing else can wait for them
+    for child in process.children.clone() {
+        let child = processes.get_mut(&child).unwrap();
+        if let State::Ready { .. } = child.state {
+            child.state = State::Exited(KILLED);
+            paging::free_address_space(child.p4);
+        }
+    }
+    status
+}
+
+// Drops an exited process along with its children, which have all exited by now.
+fn reap(pid: Pid) {
+    let mut processes = PROCESSES.lock();
+    let process = processes.remove(&pid).unwrap();
+    for child in &process.children {
+        processes.remove(child);
+    }
+    if let Some(parent) = processes.get_mut(&process.parent) {
+        parent.children.retain(|&child| child != pid);
+    }
+}
@@ -0,0 +1,164 @@
+//! 16550 UART, with COM1 mirroring everything printed
+use core::fmt;
+use spin::Mutex;
+use crate::util::{inb, outb};
+use lazy_static::lazy_static;
+
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const DEFAULT_BAUD: u32 = 115_200;
+const UART_CLOCK: u32 = 115_200;
+const RECEIVE_BUFFER_SIZE: usize = 256;
+
+// registers, from the base port
+const DATA: u16 = 0; // divisor low byte while DLAB is set
+const INTERRUPT_ENABLE: u16 = 1; // divisor high byte while DLAB is set
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const DLAB: u8 = 1 << 7;
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1));
+}
+
+static RECEIVED: Mutex<RingBuffer> = Mutex::new(RingBuffer { bytes: [0; RECEIVE_BUFFER_SIZE], start: 0, len: 0 });
+
+pub struct SerialPort {
+    base: u16,
+    present: bool,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base, present: false }
+    }
+
+    /// Sets up 8N1 at `baud` with the FIFO on. Returns false if the loopback
+    /// test fails, in which case the port stays silent.
+    pub fn init(&mut self, baud: u32) -> bool {
+        let base = self.base;
+        outb(base + INTERRUPT_ENABLE, 0);
+        self.set_baud(baud);
+        outb(base + LINE_CONTROL, 0x03); // 8 bits, no parity, one stop bit
+        outb(base + FIFO_CONTROL, 0xC7); // enable and clear the FIFOs, interrupt at 14 bytes
+
+        // send a byte to ourselves to see if anything is there
+        outb(base + MODEM_CONTROL, 0x1E);
+        outb(base + DATA, 0xAE);
+        self.present = inb(base + DATA) == 0xAE;
+
+        outb(base + MODEM_CONTROL, 0x0F); // DTR, RTS and OUT2, which gates the irq
+        self.present
+    }
+
+    pub fn set_baud(&mut self, baud: u32) {
+        let divisor = (UART_CLOCK / baud.clamp(1, UART_CLOCK)) as u16;
+        let line_control = inb(self.base + LINE_CONTROL);
+        outb(self.base + LINE_CONTROL, line_control | DLAB);
+        outb(self.base + DATA, divisor as u8);
+        outb(self.base + INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        outb(self.base + LINE_CONTROL, line_control & !DLAB);
+    }
+
+    pub fn enable_receive_interrupt(&mut self) {
+        outb(self.base + INTERRUPT_ENABLE, 0x01);
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        while inb(self.base + LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        outb(self.base + DATA, byte);
+    }
+
+    pub fn receive(&mut self) -> Option<u8> {
+        (self.present && inb(self.base + LINE_STATUS) & DATA_READY != 0).then(|| inb(self.base + DATA))
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+// bytes that came in on COM1, the oldest are dropped when it's full
+struct RingBuffer {
+    bytes: [u8; RECEIVE_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn push(&mut self, byte: u8) {
+        if self.len == RECEIVE_BUFFER_SIZE {
+            self.start = (self.start + 1) % RECEIVE_BUFFER_SIZE;
+            self.len -= 1;
+        }
+        self.bytes[(self.start + self.len) % RECEIVE_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        (self.len > 0).then(|| {
+            let byte = self.bytes[self.start];
+            self.start = (self.start + 1) % RECEIVE_BUFFER_SIZE;
+            self.len -= 1;
+            byte
+        })
+    }
+}
+
+pub fn init() {
+    let mut port = SERIAL1.lock();
+    if port.init(DEFAULT_BAUD) {
+        port.enable_receive_interrupt();
+        crate::interrupts::pic::PICS.lock().unmask(4);
+    }
+}
+
+/// Called from the IRQ 4 handler, empties the receive FIFO into the buffer.
+pub fn interrupt() {
+    let mut port = SERIAL1.lock();
+    let mut received = RECEIVED.lock();
+    while let Some(byte) = port.receive() {
+        received.push(byte);
+    }
+}
+
+/// The oldest byte received on COM1 that nobody has read yet.
+pub fn read_byte() -> Option<u8> {
+    crate::interrupts::without_interrupts(|| RECEIVED.lock().pop())
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    crate::interrupts::without_interrupts(|| SERIAL1.lock().write_fmt(args).unwrap());
+}
+
+/// Like `print!`, but only to COM1.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
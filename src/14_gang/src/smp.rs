@@ -0,0 +1,100 @@
+use alloc::vec;
+use log::{info, warn};
+use core::ptr::addr_of;
+use multiboot2::BootInformation;
+use crate::interrupts::{idt, lapic};
+use crate::interrupts::pit::{self, sleep_busy};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const TRAMPOLINE: usize = 0x8000; // must match trampoline.asm
+const STACK_SIZE: usize = 4096 * 4;
+
+pub static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_data: u8;
+}
+
+#[repr(C)]
+struct TrampolineData {
+    p4: u64,
+    stack: u64,
+    entry: u64,
+    cpu_id: u64,
+}
+
+pub fn init(boot_info: &BootInformation) {
+    let Some(madt) = crate::acpi::madt(boot_info) else {
+        warn!("No MADT found, staying on one CPU");
+        return;
+    };
+    lapic::init(madt.local_apic_address);
+    lapic::calibrate_timer();
+
+    let start = addr_of!(trampoline_start) as usize;
+    let end = addr_of!(trampoline_end) as usize;
+    let data = addr_of!(trampoline_data) as usize;
+    unsafe { core::ptr::copy_nonoverlapping(start as *const u8, TRAMPOLINE as *mut u8, end - start) };
+    let data = unsafe { &mut *((TRAMPOLINE + data - start) as *mut TrampolineData) };
+
+    let bsp = lapic::id();
+    for &apic_id in madt.apic_ids.iter().filter(|&&id| id != bsp) {
+        let cpu_id = CPU_COUNT.load(Ordering::SeqCst);
+        let stack = vec![0u8; STACK_SIZE].leak();
+
+        data.p4 = crate::memory::paging::active_p4() as u64;
+        data.stack = stack.as_ptr() as u64 + STACK_SIZE as u64;
+        data.entry = ap_main as extern "C" fn(usize) -> ! as u64;
+        data.cpu_id = cpu_id as u64;
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        // init, then startup, and a second startup if the first one didn't take
+        lapic::send_init(apic_id);
+        sleep_busy(20); // at least the 10ms needed, ticks are 10ms apart
+        lapic::send_startup(apic_id, (TRAMPOLINE / 4096) as u8);
+        if !wait_for_ap(10) {
+            lapic::send_startup(apic_id, (TRAMPOLINE / 4096) as u8);
+            if !wait_for_ap(1000) {
+                // it may still come up late and read the trampoline data, so
+                // nothing else gets to use it, or that stack and id, after this
+                warn!("CPU with APIC id {} didn't start, not starting any more", apic_id);
+                break;
+            }
+        }
+    }
+    info!("{} CPUs online", CPU_COUNT.load(Ordering::SeqCst));
+}
+
+fn wait_for_ap(milliseconds: u32) -> bool {
+    let target = pit::ticks() + pit::ms_to_ticks(milliseconds).max(1);
+    while pit::ticks() <= target {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+    }
+    false
+}
+
+extern "C" fn ap_main(cpu_id: usize) -> ! {
+    crate::util::init();
+    crate::cpu::init(cpu_id);
+    idt::init();
+    lapic::enable();
+    crate::syscall::init();
+
+    // counted here so one that comes up after the BSP gave up on it counts too,
+    // and the trampoline data can be reused for the next CPU from here on
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    info!("CPU {} online", crate::cpu::current().id);
+
+    // ticks come from the BSP, the timer gets us out of hlt to see them
+    lapic::start_timer();
+    crate::interrupts::enable();
+
+    // nothing to run here yet
+    crate::util::hlt_loop()
+}
@@ -0,0 +1,42 @@
+use super::{MutexGuard, WaitQueue};
+
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { queue: WaitQueue::new() }
+    }
+
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    /// Returns the reacquired guard and whether the timeout ran out.
+    pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T>, timeout: u32) -> (MutexGuard<'a, T>, bool) {
+        self.wait_inner(guard, Some(timeout))
+    }
+
+    pub fn wait_while<'a, T>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.queue.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.queue.wake_all()
+    }
+
+    fn wait_inner<'a, T>(&self, guard: MutexGuard<'a, T>, timeout: Option<u32>) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        // unlock only once we're queued, so a notify right after can't be missed
+        let woken = self.queue.wait_if(|| { drop(guard); true }, timeout);
+        (mutex.lock(), !woken)
+    }
+}
@@ -0,0 +1,13 @@
+//! Blocking synchronization primitives
+// waiters are always served in arrival order and there are no priorities,
+// so nobody starves and there is nothing to inherit
+
+mod mutex;
+mod condvar;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
+pub use mutex::{Mutex, MutexGuard};
@@ -0,0 +1,90 @@
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Sleeping mutex. Unlocking hands the lock straight to the first waiter, so
+/// newcomers can't barge past the queue.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.queue.wait_if(|| !self.try_acquire(), None);
+        MutexGuard { mutex: self }
+    }
+
+    pub fn lock_timeout(&self, timeout: u32) -> Option<MutexGuard<'_, T>> {
+        // the guard must only exist once the lock is ours, dropping one unlocks
+        self.queue.wait_if(|| !self.try_acquire(), Some(timeout))
+            .then(|| MutexGuard { mutex: self })
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_acquire().then(|| MutexGuard { mutex: self })
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn unlock(&self) {
+        // with someone waiting the lock stays taken, it's theirs now
+        self.queue.wake_one_or(|| self.locked.store(false, Ordering::Release));
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+// sharing a guard shares the data, which needs more than the Send the mutex asks for
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mutex;
+    use core::sync::atomic::Ordering;
+
+    #[test_case]
+    fn failed_try_lock_keeps_the_lock() {
+        let mutex = Mutex::new(0);
+        let _guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        assert!(mutex.locked.load(Ordering::Relaxed));
+        assert!(mutex.try_lock().is_none());
+    }
+}
@@ -0,0 +1,35 @@
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counting semaphore. `release` is safe to call from irq handlers, and hands
+/// the permit directly to the first waiter if there is one.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { permits: AtomicUsize::new(permits), queue: WaitQueue::new() }
+    }
+
+    pub fn acquire(&self) {
+        self.queue.wait_if(|| !self.try_acquire(), None);
+    }
+
+    pub fn acquire_timeout(&self, timeout: u32) -> bool {
+        self.queue.wait_if(|| !self.try_acquire(), Some(timeout))
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits.fetch_update(Ordering::Acquire, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok()
+    }
+
+    pub fn release(&self) {
+        self.queue.wake_one_or(|| { self.permits.fetch_add(1, Ordering::Release); });
+    }
+
+    pub fn permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,116 @@
+use spin::Mutex;
+use core::ptr::NonNull;
+use alloc::collections::VecDeque;
+use crate::interrupts::{self, pit};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// lives on the stack of whoever is waiting
+struct Waiter {
+    woken: AtomicBool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct WaiterRef(NonNull<Waiter>);
+
+unsafe impl Send for WaiterRef {}
+
+impl WaiterRef {
+    // must be called with the queue locked, the waiter may return as soon as this is stored
+    fn wake(self) {
+        unsafe { self.0.as_ref() }.woken.store(true, Ordering::Release);
+    }
+}
+
+/// FIFO queue of parked waiters. There is no scheduler to hand the CPU to, so
+/// parking halts the CPU until an interrupt handler (or another CPU) wakes us.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<WaiterRef>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Parks the caller if `should_wait` returns true. The check runs with the
+    /// queue locked, so a wakeup can't get lost between checking and queueing.
+    /// Returns false if `timeout` (in ms) ran out before anyone woke us up.
+    pub fn wait_if(&self, should_wait: impl FnOnce() -> bool, timeout: Option<u32>) -> bool {
+        assert!(!crate::cpu::current().in_interrupt(), "can't wait inside an interrupt handler");
+        assert!(interrupts::are_enabled(), "waiting with interrupts disabled would never wake up");
+
+        let waiter = Waiter { woken: AtomicBool::new(false) };
+        let this = WaiterRef(NonNull::from(&waiter));
+
+        let queued = self.locked(|waiters| {
+            let wait = should_wait();
+            if wait {
+                waiters.push_back(this);
+            }
+            wait
+        });
+        if !queued {
+            return true;
+        }
+
+        // the deadline check below compares wrapped differences as i32
+        let deadline = timeout.map(|ms| pit::ticks().wrapping_add(pit::ms_to_ticks(ms).clamp(1, i32::MAX as u32)));
+        loop {
+            interrupts::disable();
+            if waiter.woken.load(Ordering::Acquire) {
+                interrupts::enable();
+                return true;
+            }
+
+            if let Some(deadline) = deadline {
+                if pit::ticks().wrapping_sub(deadline) as i32 >= 0 {
+                    // if we're not in the queue anymore, a waker got to us first and the wakeup counts
+                    let timed_out = self.locked(|waiters| {
+                        let index = waiters.iter().position(|&waiter| waiter == this);
+                        index.map(|index| waiters.remove(index)).is_some()
+                    });
+                    interrupts::enable();
+                    return !timed_out;
+                }
+            }
+
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    pub fn wake_one(&self) -> bool {
+        self.wake_one_or(|| ())
+    }
+
+    /// Wakes the longest waiting waiter, or runs `otherwise` with the queue
+    /// still locked if nobody is waiting.
+    pub fn wake_one_or(&self, otherwise: impl FnOnce()) -> bool {
+        self.locked(|waiters| match waiters.pop_front() {
+            Some(waiter) => {
+                waiter.wake();
+                true
+            },
+            None => {
+                otherwise();
+                false
+            },
+        })
+    }
+
+    pub fn wake_all(&self) -> usize {
+        self.locked(|waiters| {
+            let count = waiters.len();
+            waiters.drain(..).for_each(WaiterRef::wake);
+            count
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locked(|waiters| waiters.is_empty())
+    }
+
+    // irq handlers wake waiters too, so the lock can't be held with interrupts on
+    fn locked<R>(&self, f: impl FnOnce(&mut VecDeque<WaiterRef>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.waiters.lock()))
+    }
+}
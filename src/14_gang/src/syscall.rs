@@ -0,0 +1,224 @@
+//! System calls, through `syscall` or `int 0x80`
+//!
+//! The number goes in rax and up to six arguments in rdi, rsi, rdx, r10, r8
+//! and r9. The result comes back in rax, negative values are errors. Every
+//! other register is preserved, except rcx and r11 which `syscall` clobbers.
+use alloc::sync::Arc;
+use crate::fs::{self, File, SeekFrom};
+use core::arch::global_asm;
+use crate::util::{rdmsr, wrmsr};
+use crate::cpu::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET};
+use crate::interrupts::gdt::{KERNEL_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+pub const WRITE: u64 = 0;
+pub const EXIT: u64 = 1;
+pub const SLEEP: u64 = 2;
+pub const GETPID: u64 = 3;
+pub const YIELD: u64 = 4;
+pub const READ: u64 = 5;
+pub const OPEN: u64 = 6;
+pub const CLOSE: u64 = 7;
+pub const SEEK: u64 = 8;
+
+// whence for seek
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+const MAX_SLEEP_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(i64)]
+pub enum Error {
+    NotFound = 2,
+    Io = 5,
+    BadFileDescriptor = 9,
+    Fault = 14,
+    AlreadyExists = 17,
+    CrossDevice = 18,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    NoSpace = 28,
+    ReadOnly = 30,
+    NoSuchSyscall = 38,
+    NotEmpty = 39,
+}
+
+impl From<fs::Error> for Error {
+    fn from(error: fs::Error) -> Self {
+        match error {
+            fs::Error::NotFound => Error::NotFound,
+            fs::Error::NotADirectory => Error::NotADirectory,
+            fs::Error::IsADirectory => Error::IsADirectory,
+            fs::Error::AlreadyExists => Error::AlreadyExists,
+            fs::Error::NotEmpty => Error::NotEmpty,
+            fs::Error::ReadOnly => Error::ReadOnly,
+            fs::Error::InvalidPath | fs::Error::InvalidArgument => Error::InvalidArgument,
+            fs::Error::CrossDevice => Error::CrossDevice,
+            fs::Error::BadFileDescriptor => Error::BadFileDescriptor,
+            fs::Error::NoSpace => Error::NoSpace,
+            fs::Error::Io => Error::Io,
+        }
+    }
+}
+
+type Handler = fn([u64; 6]) -> Result<u64, Error>;
+
+// indexed by syscall number, only ever append to this
+static SYSCALLS: [Handler; 9] = [write, exit, sleep, getpid, yield_now, read, open, close, seek];
+
+const EFER_MSR: u32 = 0xC000_0080;
+const STAR_MSR: u32 = 0xC000_0081;
+const LSTAR_MSR: u32 = 0xC000_0082;
+const SFMASK_MSR: u32 = 0xC000_0084;
+
+extern "C" {
+    fn syscall_entry();
+}
+
+#[repr(C)]
+struct Registers {
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rax: u64,
+}
+
+macro_rules! push_registers {
+    () => { "push rax\npush rdi\npush rsi\npush rdx\npush r10\npush r8\npush r9" };
+}
+
+macro_rules! pop_registers {
+    () => { "pop r9\npop r8\npop r10\npop rdx\npop rsi\npop rdi\npop rax" };
+}
+
+// syscall leaves the user stack and GS base in place, so swap in the kernel's
+// before touching anything. rcx and r11 hold the user rip and rflags for sysret.
+// int 0x80 comes from ring 3 too, unless the kernel uses it, which the saved cs tells.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "push qword ptr gs:[{user_stack}]",
+    "push rcx",
+    "push r11",
+    push_registers!(),
+    "mov rdi, rsp",
+    "sti",
+    "call {handler}",
+    "cli",
+    pop_registers!(),
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+
+    ".global syscall_interrupt",
+    "syscall_interrupt:",
+    "test byte ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    push_registers!(),
+    "mov rdi, rsp",
+    "sti",
+    "call {handler}",
+    "cli",
+    pop_registers!(),
+    "test byte ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "iretq",
+    user_stack = const USER_STACK_OFFSET,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    handler = sym syscall_handler,
+);
+
+extern "C" fn syscall_handler(registers: &mut Registers) {
+    let arguments = [registers.rdi, registers.rsi, registers.rdx, registers.r10, registers.r8, registers.r9];
+    let result = SYSCALLS.get(registers.rax as usize)
+        .ok_or(Error::NoSuchSyscall)
+        .and_then(|handler| handler(arguments));
+
+    registers.rax = match result {
+        Ok(value) => value,
+        Err(error) => -(error as i64) as u64,
+    };
+}
+
+pub fn init() {
+    // sysret adds 8 for ss and 16 for cs to the selector in the top half of STAR
+    let sysret_base = USER_DATA_SELECTOR.0 as u64 - 8;
+    let star = sysret_base << 48 | (KERNEL_CODE_SELECTOR.0 as u64) << 32;
+
+    wrmsr(EFER_MSR, rdmsr(EFER_MSR) | 1); // Set SCE bit
+    wrmsr(STAR_MSR, star);
+    wrmsr(LSTAR_MSR, syscall_entry as unsafe extern "C" fn() as u64);
+    wrmsr(SFMASK_MSR, 1 << 9 | 1 << 8 | 1 << 10); // interrupts, trap and direction flag off on entry
+}
+
+fn write([fd, buffer, len, ..]: [u64; 6]) -> Result<u64, Error> {
+    let bytes = crate::usermode::user_slice(buffer, len).ok_or(Error::Fault)?;
+    Ok(file(fd)?.write(bytes)? as u64)
+}
+
+fn exit([status, ..]: [u64; 6]) -> Result<u64, Error> {
+    crate::usermode::exit(status as i64)
+}
+
+fn sleep([milliseconds, ..]: [u64; 6]) -> Result<u64, Error> {
+    // nothing can wake a sleeping process early, so a day is as long as it gets
+    crate::interrupts::pit::sleep(milliseconds.min(MAX_SLEEP_MS) as u32);
+    Ok(0)
+}
+
+fn getpid(_: [u64; 6]) -> Result<u64, Error> {
+    Ok(crate::process::current() as u64)
+}
+
+fn yield_now(_: [u64; 6]) -> Result<u64, Error> {
+    Ok(0) // nothing else to run without a scheduler
+}
+
+fn file(fd: u64) -> Result<Arc<File>, Error> {
+    crate::process::with_current(|process| process.file(fd as usize))
+        .flatten()
+        .ok_or(Error::BadFileDescriptor)
+}
+
+fn read([fd, buffer, len, ..]: [u64; 6]) -> Result<u64, Error> {
+    let bytes = crate::usermode::user_slice_mut(buffer, len).ok_or(Error::Fault)?;
+    Ok(file(fd)?.read(bytes)? as u64)
+}
+
+fn open([path, len, flags, ..]: [u64; 6]) -> Result<u64, Error> {
+    let path = crate::usermode::user_slice(path, len).ok_or(Error::Fault)?;
+    let path = core::str::from_utf8(path).map_err(|_| Error::InvalidArgument)?;
+    let file = fs::open(path, flags as u32)?;
+    crate::process::with_current(|process| process.add_file(file) as u64).ok_or(Error::BadFileDescriptor)
+}
+
+fn close([fd, ..]: [u64; 6]) -> Result<u64, Error> {
+    match crate::process::with_current(|process| process.close(fd as usize)) {
+        Some(true) => Ok(0),
+        _ => Err(Error::BadFileDescriptor),
+    }
+}
+
+fn seek([fd, offset, whence, ..]: [u64; 6]) -> Result<u64, Error> {
+    let position = match whence {
+        SEEK_SET => SeekFrom::Start(offset as usize),
+        SEEK_CUR => SeekFrom::Current(offset as i64 as isize),
+        SEEK_END => SeekFrom::End(offset as i64 as isize),
+        _ => return Err(Error::InvalidArgument),
+    };
+    Ok(file(fd)?.seek(position)? as u64)
+}
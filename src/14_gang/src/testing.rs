@@ -0,0 +1,44 @@
+//! Runs `#[test_case]` functions inside the kernel under QEMU
+//!
+//! Results go to COM1, and QEMU's isa-debug-exit device turns the outcome into
+//! its exit status: (code << 1) | 1, so 33 for success and 35 for failure.
+use core::any::type_name;
+use crate::util::outb;
+
+const ISA_DEBUG_EXIT: u16 = 0xf4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{} ... ", type_name::<T>());
+        self();
+        serial_println!("ok");
+    }
+}
+
+/// The test runner, a failing test panics and the panic handler exits QEMU.
+pub fn run(tests: &[&dyn Testable]) {
+    serial_println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    serial_println!("test result: ok. {} passed", tests.len());
+    exit_qemu(ExitCode::Success);
+}
+
+/// Exits QEMU if it was started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`,
+/// halts otherwise.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    outb(ISA_DEBUG_EXIT, code as u8);
+    crate::panic::halt()
+}
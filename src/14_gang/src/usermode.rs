@@ -0,0 +1,98 @@
+//! Dropping to ring 3
+use core::arch::global_asm;
+use crate::cpu::{KERNEL_STACK_OFFSET, RSP0_OFFSET};
+use crate::interrupts::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+// everything between the kernel's first P4 entry and the canonical hole
+pub const USER_START: usize = 0x0000_0080_0000_0000;
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+const RFLAGS: u64 = 1 << 9 | 1 << 1; // interrupts on, bit 1 is always set
+
+extern "C" {
+    fn usermode_enter(entry: u64, user_stack: u64) -> i64;
+    fn usermode_exit(status: i64) -> !;
+}
+
+// The kernel stack below the saved registers becomes the stack for syscalls
+// and traps from ring 3, and exiting unwinds straight back to usermode_enter's
+// caller. The previous kernel stack is saved too, so this can nest. Exiting
+// always happens from a syscall or trap, which already swapped the GS base back.
+global_asm!(
+    ".global usermode_enter",
+    "usermode_enter:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "push qword ptr gs:[{kernel_stack}]",
+    "mov gs:[{kernel_stack}], rsp",
+    "mov gs:[{rsp0}], rsp",
+    "push {data}",                      // ss
+    "push rsi",                         // rsp
+    "push {rflags}",                    // rflags
+    "push {code}",                      // cs
+    "push rdi",                         // rip
+    "cli",                              // nothing may come in with the user's GS base
+    "swapgs",
+    "iretq",
+
+    ".global usermode_exit",
+    "usermode_exit:",
+    "mov rsp, gs:[{kernel_stack}]",
+    "mov rax, rdi",
+    "pop rdi",
+    "mov gs:[{kernel_stack}], rdi",
+    "mov gs:[{rsp0}], rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    rsp0 = const RSP0_OFFSET,
+    data = const USER_DATA_SELECTOR.0,
+    code = const USER_CODE_SELECTOR.0,
+    rflags = const RFLAGS,
+);
+
+/// Runs `entry` in ring 3 until it makes the exit syscall, and returns its exit
+/// status. Both addresses have to be mapped with the user bit set.
+pub unsafe fn run(entry: u64, user_stack: u64) -> i64 {
+    usermode_enter(entry, user_stack)
+}
+
+/// Abandons whatever ring 3 was doing and returns `status` from `run`.
+pub fn exit(status: i64) -> ! {
+    unsafe { usermode_exit(status) }
+}
+
+/// The buffer a user pointer refers to, if all of it is mapped in user space.
+pub fn user_slice<'a>(address: u64, len: u64) -> Option<&'a [u8]> {
+    user_range_mapped(address, len, 0).then(|| unsafe { core::slice::from_raw_parts(address as *const u8, len as usize) })
+}
+
+/// Like `user_slice`, but every page also has to be writable.
+pub fn user_slice_mut<'a>(address: u64, len: u64) -> Option<&'a mut [u8]> {
+    let writable = crate::memory::paging::WRITABLE;
+    user_range_mapped(address, len, writable).then(|| unsafe { core::slice::from_raw_parts_mut(address as *mut u8, len as usize) })
+}
+
+fn user_range_mapped(address: u64, len: u64, flags: u64) -> bool {
+    let (start, len) = (address as usize, len as usize);
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+    if start < USER_START || end > USER_END {
+        return false;
+    }
+    let p4 = crate::memory::paging::active_p4();
+    let page_size = crate::memory::paging::PAGE_SIZE;
+    (start & !(page_size - 1)..end)
+        .step_by(page_size)
+        .all(|page| crate::memory::paging::flags(p4, page).is_some_and(|existing| existing & flags == flags))
+}
@@ -6,11 +6,9 @@ pub fn init() {
 }
 
 fn enable_nxe_bit() {
-    let efer_msr: u64 = 0xC000_0080; // EFER MSR number
-    let mut efer: u64;
-    unsafe { asm!("rdmsr", in("ecx") efer_msr, out("eax") efer, out("edx") _, options(nostack)); } // Read EFER
-    efer |= 1 << 11; // Set NXE bit
-    unsafe { asm!("wrmsr", in("ecx") efer_msr, in("eax") efer, in("edx") 0, options(nostack)); } // Write EFER
+    let efer_msr: u32 = 0xC000_0080; // EFER MSR number
+    let efer = rdmsr(efer_msr); // Read EFER
+    wrmsr(efer_msr, efer | 1 << 11); // Set NXE bit
 }
 
 fn enable_write_protect_bit() {
@@ -20,6 +18,16 @@ fn enable_write_protect_bit() {
     unsafe { asm!("mov cr0, {}", in(reg) cr0, options(nostack)); } // Write CR0
 }
 
+pub fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nostack)); }
+    (high as u64) << 32 | low as u64
+}
+
+pub fn wrmsr(msr: u32, value: u64) {
+    unsafe { asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack)); }
+}
+
 pub fn outb(port: u16, value: u8) {
     unsafe {
         asm!(
@@ -44,6 +52,30 @@ pub fn inb(port: u16) -> u8 {
     value
 }
 
+pub fn outw(port: u16, value: u16) {
+    unsafe {
+        asm!(
+            "out dx, ax",
+            in("dx") port,
+            in("ax") value,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+pub fn inw(port: u16) -> u16 {
+    let value: u16;
+    unsafe {
+        asm!(
+            "in ax, dx",
+            in("dx") port,
+            out("ax") value,
+            options(nostack, preserves_flags),
+        );
+    }
+    value
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         unsafe {
@@ -54,4 +86,39 @@ pub fn hlt_loop() -> ! {
 
 pub const fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
-}
\ No newline at end of file
+}
+
+/// CRC-32 as used by GPT, zlib and ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut index = 0;
+        while index < 256 {
+            let mut crc = index as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[index] = crc;
+            index += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0, |crc, &byte| TABLE[(crc as u8 ^ byte) as usize] ^ crc >> 8)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test_case]
+    fn crc32_check_value() {
+        assert_eq!(super::crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(super::crc32(b""), 0);
+    }
+
+    #[test_case]
+    fn align_up_rounds_to_the_next_multiple() {
+        assert_eq!(super::align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(super::align_up(0x2000, 0x1000), 0x2000);
+    }
+}
@@ -1,57 +1,203 @@
 use core::fmt;
 use spin::Mutex;
-use crate::util::outb;
+use crate::util::{inb, outb};
 use lazy_static::lazy_static;
 
-const VGA_WIDTH: usize = 80;
-const VGA_HEIGHT: usize = 25;
+pub const VGA_WIDTH: usize = 80;
+pub const VGA_HEIGHT: usize = 25;
+const TAB_WIDTH: usize = 8;
+const MAX_PARAMETERS: usize = 8;
+const DEFAULT_COLOR: VgaColor = VgaColor::new(Color::White, Color::Black);
+
+// ANSI color numbers are red, green, blue bits, VGA has blue, green, red
+const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+// shown for anything code page 437 has nothing close to
+const REPLACEMENT: u8 = 0xFE; // ■
+
+// code page 437 glyphs for 0x01 to 0x1F, which ASCII uses for control characters
+const CP437_LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+// code page 437 from 0x80 up
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-        color: VgaColor::new(Color::White, Color::Black),
-        column: 0,
-        row: 0,
+    pub static ref WRITER: Mutex<Writer> = Mutex::new({
+        disable_blink();
+        Writer {
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            color: DEFAULT_COLOR,
+            column: 0,
+            row: 0,
+            saved: (0, 0),
+            bold: false,
+            escape: Escape::None,
+        }
     });
 }
 
+// Attribute bit 7 blinks by default, which leaves only 8 background colors.
+// Turning it off in the attribute controller's mode register makes it the bright bit.
+fn disable_blink() {
+    inb(0x3DA); // resets the index/data flip-flop at 0x3C0 to index
+    outb(0x3C0, 0x10 | 0x20); // mode control, keeping the palette enabled
+    let mode = inb(0x3C1);
+    outb(0x3C0, mode & !(1 << 3));
+}
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[VgaEntry; VGA_WIDTH]; VGA_HEIGHT],
 }
 
-#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
     Black, Blue, Green, Cyan, Red, Magenta, Brown, LightGrey, DarkGrey, 
     LightBlue, LightGreen, LightCyan, LightRed, Pink, Yellow, White,
 }
 
-#[derive(Clone, Copy)]
+impl Color {
+    const ALL: [Color; 16] = [
+        Color::Black, Color::Blue, Color::Green, Color::Cyan, Color::Red, Color::Magenta, Color::Brown, Color::LightGrey,
+        Color::DarkGrey, Color::LightBlue, Color::LightGreen, Color::LightCyan, Color::LightRed, Color::Pink, Color::Yellow, Color::White,
+    ];
+
+    // the low 4 bits
+    fn from_bits(bits: u8) -> Color {
+        Color::ALL[(bits & 0x0F) as usize]
+    }
+}
+
+/// Foreground and background packed the way the VGA buffer stores them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct VgaColor(u8);
+pub struct VgaColor(u8);
 
 impl VgaColor {
-    fn new(foreground: Color, background: Color) -> VgaColor {
+    pub const fn new(foreground: Color, background: Color) -> VgaColor {
         VgaColor((background as u8) << 4 | (foreground as u8))
     }
+
+    pub fn foreground(self) -> Color {
+        Color::from_bits(self.0)
+    }
+
+    pub fn background(self) -> Color {
+        Color::from_bits(self.0 >> 4)
+    }
+
+    fn with_foreground(self, foreground: u8) -> VgaColor {
+        VgaColor(self.0 & 0xF0 | foreground & 0x0F)
+    }
+
+    fn with_background(self, background: u8) -> VgaColor {
+        VgaColor((background & 0x0F) << 4 | self.0 & 0x0F)
+    }
 }
 
+// where we are in an escape sequence
 #[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Escape, // after ESC
+    Csi { parameters: [u16; MAX_PARAMETERS], count: usize }, // after ESC [
+}
+
+/// One character cell, the character being a code page 437 byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct VgaEntry {
-    character: u8,
-    color: VgaColor,
+pub struct VgaEntry {
+    pub character: u8,
+    pub color: VgaColor,
 }
 
+/// Text mode console. Understands the usual VT100/ANSI escape sequences: SGR
+/// colors, cursor movement, erasing, and saving and restoring the cursor.
 pub struct Writer {
     buffer: &'static mut Buffer,
     color: VgaColor,
     column: usize,
     row: usize,
+    saved: (usize, usize), // row and column for ESC 7 and ESC [ s
+    bold: bool,
+    escape: Escape,
 }
 
 impl Writer {
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color = VgaColor::new(foreground, background);
+    }
+
+    pub fn set_foreground(&mut self, foreground: Color) {
+        self.color = self.color.with_foreground(foreground as u8);
+    }
+
+    pub fn set_background(&mut self, background: Color) {
+        self.color = self.color.with_background(background as u8);
+    }
+
+    /// The color text is written in.
+    pub fn color(&self) -> VgaColor {
+        self.color
+    }
+
+    pub fn set_vga_color(&mut self, color: VgaColor) {
+        self.color = color;
+    }
+
+    /// Row and column the next character goes to.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.column.min(VGA_WIDTH - 1))
+    }
+
+    pub fn set_cursor(&mut self, row: usize, column: usize) {
+        self.row = row.min(VGA_HEIGHT - 1);
+        self.column = column.min(VGA_WIDTH - 1);
+        self.move_cursor();
+    }
+
+    /// Puts a cell anywhere on screen, leaving the cursor alone. Out of bounds is ignored.
+    pub fn write_at(&mut self, row: usize, column: usize, entry: VgaEntry) {
+        if let Some(cell) = self.buffer.chars.get_mut(row).and_then(|cells| cells.get_mut(column)) {
+            *cell = entry;
+        }
+    }
+
+    pub fn read_at(&self, row: usize, column: usize) -> Option<VgaEntry> {
+        self.buffer.chars.get(row)?.get(column).copied()
+    }
+
+    /// The hardware cursor covers scanlines `start` to `end` of a cell, 0 to 15
+    /// from the top. The BIOS default is 13 to 14, an underline.
+    pub fn set_cursor_shape(&mut self, start: u8, end: u8) {
+        outb(0x3D4, 0x0A);
+        outb(0x3D5, start & 0x1F);
+        outb(0x3D4, 0x0B);
+        outb(0x3D5, end & 0x1F);
+    }
+
+    pub fn hide_cursor(&mut self) {
+        outb(0x3D4, 0x0A);
+        outb(0x3D5, 0x20); // bit 5 turns it off
+    }
+
+    pub fn show_cursor(&mut self) {
+        self.set_cursor_shape(13, 14);
+    }
+
     fn move_cursor(&self) {
         let pos = self.row * VGA_WIDTH + self.column;
         outb(0x3D4, 0x0F);
@@ -63,28 +209,168 @@ impl Writer {
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
-            byte => {
-                if self.column >= VGA_WIDTH {
-                    self.new_line();
+            b'\r' => self.column = 0,
+            b'\t' => {
+                let stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < stop.min(VGA_WIDTH) {
+                    self.write_byte(b' ');
                 }
-                self.buffer.chars[self.row][self.column] = VgaEntry {
-                    character: byte,
-                    color: self.color
-                };
-                self.column += 1;
-            }
+            },
+            0x08 => self.column = self.column.min(VGA_WIDTH - 1).saturating_sub(1), // backspace
+            byte => self.put(byte),
+        }
+    }
+
+    // a glyph in the next cell, even the ones sharing a byte with a control character
+    fn put(&mut self, byte: u8) {
+        if self.column >= VGA_WIDTH {
+            self.new_line();
         }
+        self.buffer.chars[self.row][self.column] = VgaEntry {
+            character: byte,
+            color: self.color
+        };
+        self.column += 1;
     }
 
     fn write_string(&mut self, string: &str) {
-        for byte in string.bytes() {
-            if (0x20..=0x7e).contains(&byte) || byte == b'\n' {
-                self.write_byte(byte);
+        for character in string.chars() {
+            match (character.is_ascii(), self.escape) {
+                (true, _) => self.process(character as u8),
+                (false, Escape::None) => self.put(to_cp437(character)),
+                (false, _) => self.escape = Escape::None, // garbage in a sequence ends it
             }
         }
         self.move_cursor();
     }
 
+    // one byte through the escape sequence state machine
+    fn process(&mut self, byte: u8) {
+        self.escape = match (self.escape, byte) {
+            (Escape::None, 0x1B) => Escape::Escape,
+            (Escape::None, byte) => {
+                if (0x20..=0x7e).contains(&byte) || matches!(byte, b'\n' | b'\r' | b'\t' | 0x08) {
+                    self.write_byte(byte);
+                }
+                Escape::None
+            },
+            (Escape::Escape, b'[') => Escape::Csi { parameters: [0; MAX_PARAMETERS], count: 0 },
+            (Escape::Escape, b'7') => {
+                self.saved = (self.row, self.column);
+                Escape::None
+            },
+            (Escape::Escape, b'8') => {
+                (self.row, self.column) = self.saved;
+                Escape::None
+            },
+            (Escape::Escape, b'c') => {
+                self.reset();
+                Escape::None
+            },
+            (Escape::Escape, _) => Escape::None,
+            (Escape::Csi { mut parameters, count }, b'0'..=b'9') => {
+                let parameter = &mut parameters[count.min(MAX_PARAMETERS - 1)];
+                *parameter = parameter.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                Escape::Csi { parameters, count }
+            },
+            (Escape::Csi { parameters, count }, b';') => Escape::Csi { parameters, count: count + 1 },
+            // final byte
+            (Escape::Csi { parameters, count }, 0x40..=0x7E) => {
+                let count = (count + 1).min(MAX_PARAMETERS);
+                self.control_sequence(byte, &parameters[..count]);
+                Escape::None
+            },
+            // private markers like ? and intermediate bytes don't change anything we support
+            (Escape::Csi { .. }, 0x20..=0x3F) => self.escape,
+            (Escape::Csi { .. }, _) => Escape::None,
+        };
+    }
+
+    // ESC [ parameters final. Missing parameters are 0, which movements treat as 1.
+    fn control_sequence(&mut self, command: u8, parameters: &[u16]) {
+        let first = parameters[0] as usize;
+        let steps = first.max(1);
+        match command {
+            b'A' => self.row = self.row.saturating_sub(steps),
+            b'B' => self.row = (self.row + steps).min(VGA_HEIGHT - 1),
+            b'C' => self.column = (self.column + steps).min(VGA_WIDTH - 1),
+            b'D' => self.column = self.column.min(VGA_WIDTH - 1).saturating_sub(steps),
+            b'E' => (self.row, self.column) = ((self.row + steps).min(VGA_HEIGHT - 1), 0),
+            b'F' => (self.row, self.column) = (self.row.saturating_sub(steps), 0),
+            b'G' => self.column = (steps - 1).min(VGA_WIDTH - 1),
+            b'd' => self.row = (steps - 1).min(VGA_HEIGHT - 1),
+            b'H' | b'f' => {
+                let column = parameters.get(1).map_or(1, |&column| (column as usize).max(1));
+                self.row = (steps - 1).min(VGA_HEIGHT - 1);
+                self.column = (column - 1).min(VGA_WIDTH - 1);
+            },
+            b'J' => {
+                let here = self.row * VGA_WIDTH + self.column.min(VGA_WIDTH - 1);
+                match first {
+                    0 => self.clear_cells(here..VGA_WIDTH * VGA_HEIGHT),
+                    1 => self.clear_cells(0..here + 1),
+                    _ => self.clear_cells(0..VGA_WIDTH * VGA_HEIGHT),
+                }
+            },
+            b'K' => {
+                let start = self.row * VGA_WIDTH;
+                let here = start + self.column.min(VGA_WIDTH - 1);
+                match first {
+                    0 => self.clear_cells(here..start + VGA_WIDTH),
+                    1 => self.clear_cells(start..here + 1),
+                    _ => self.clear_cells(start..start + VGA_WIDTH),
+                }
+            },
+            b'm' => parameters.iter().for_each(|&parameter| self.select_graphic_rendition(parameter)),
+            b's' => self.saved = (self.row, self.column),
+            b'u' => (self.row, self.column) = self.saved,
+            _ => {},
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, parameter: u16) {
+        let bright = if self.bold { 8 } else { 0 };
+        self.color = match parameter {
+            0 => {
+                self.bold = false;
+                DEFAULT_COLOR
+            },
+            1 => {
+                self.bold = true;
+                self.color.with_foreground(self.color.0 | 8)
+            },
+            22 => {
+                self.bold = false;
+                self.color.with_foreground(self.color.0 & 7)
+            },
+            7 => VgaColor(self.color.0 << 4 | self.color.0 >> 4), // reverse
+            30..=37 => self.color.with_foreground(ANSI_TO_VGA[parameter as usize - 30] | bright),
+            39 => self.color.with_foreground(DEFAULT_COLOR.0),
+            40..=47 => self.color.with_background(ANSI_TO_VGA[parameter as usize - 40]),
+            49 => self.color.with_background(DEFAULT_COLOR.0 >> 4),
+            90..=97 => self.color.with_foreground(ANSI_TO_VGA[parameter as usize - 90] | 8),
+            100..=107 => self.color.with_background(ANSI_TO_VGA[parameter as usize - 100] | 8),
+            _ => self.color,
+        };
+    }
+
+    fn reset(&mut self) {
+        self.color = DEFAULT_COLOR;
+        self.bold = false;
+        self.clear_cells(0..VGA_WIDTH * VGA_HEIGHT);
+        (self.row, self.column) = (0, 0);
+    }
+
+    // cells counted row by row from the top left
+    fn clear_cells(&mut self, cells: core::ops::Range<usize>) {
+        for cell in cells {
+            self.buffer.chars[cell / VGA_WIDTH][cell % VGA_WIDTH] = VgaEntry {
+                character: b' ',
+                color: self.color,
+            };
+        }
+    }
+
     fn clear_row(&mut self, row: usize) {
         for col in 0..VGA_WIDTH {
             self.buffer.chars[row][col] = VgaEntry {
@@ -115,10 +401,67 @@ impl fmt::Write for Writer {
     }
 }
 
+/// The code page 437 byte that shows `character`, or the closest thing to it.
+pub fn to_cp437(character: char) -> u8 {
+    if character.is_ascii() {
+        return character as u8;
+    }
+    if let Some(index) = CP437_HIGH.iter().position(|&glyph| glyph == character) {
+        return 0x80 + index as u8;
+    }
+    if let Some(index) = CP437_LOW.iter().position(|&glyph| glyph == character) {
+        return 0x01 + index as u8;
+    }
+    match character {
+        // no ø in 437, but φ is drawn as a slashed o
+        'ø' | 'Ø' => 0xED,
+        'β' => 0xE1,
+        'μ' => 0xE6,
+        '⌂' => 0x7F,
+        // the rest of Latin-1 loses its accents
+        'À' | 'Á' | 'Â' | 'Ã' => b'A',
+        'È' | 'Ê' | 'Ë' => b'E',
+        'Ì' | 'Í' | 'Î' | 'Ï' => b'I',
+        'Ð' => b'D',
+        'Ò' | 'Ó' | 'Ô' | 'Õ' => b'O',
+        'Ù' | 'Ú' | 'Û' => b'U',
+        'Ý' => b'Y',
+        'ã' => b'a',
+        'ð' => b'd',
+        'õ' => b'o',
+        'ý' => b'y',
+        '×' => b'x',
+        '\u{AD}' => b'-', // soft hyphen
+        '¦' => b'|',
+        '´' => b'\'',
+        '¨' => b'"',
+        '¹' => b'1',
+        '³' => b'3',
+        _ => REPLACEMENT,
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    // interrupt handlers print too, and must not find the locks taken
+    crate::interrupts::without_interrupts(|| {
+        WRITER.lock().write_fmt(args).unwrap();
+        crate::serial::_print(args);
+    });
+}
+
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    crate::interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color = writer.color();
+        writer.set_foreground(foreground);
+        writer.write_fmt(args).unwrap();
+        writer.set_vga_color(color);
+        crate::serial::_print(args);
+    });
 }
 
 #[macro_export]
@@ -132,8 +475,51 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Like `print!`, in `$color` on screen. Serial gets the text as is.
+#[macro_export]
+macro_rules! print_colored {
+    ($color:expr, $($arg:tt)*) => ($crate::vga::_print_colored($color, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println_colored {
+    ($color:expr) => ($crate::print_colored!($color, "\n"));
+    ($color:expr, $($arg:tt)*) => ($crate::print_colored!($color, "{}\n", format_args!($($arg)*)));
+}
+
 pub fn clear_screen() {
     for _ in 0..VGA_HEIGHT {
         println!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+    use super::{to_cp437, WRITER};
+
+    #[test_case]
+    fn norwegian_letters_map_to_cp437() {
+        let bytes: [u8; 7] = ['æ', 'ø', 'å', 'Æ', 'Å', '§', 'A'].map(to_cp437);
+        assert_eq!(bytes, [0x91, 0xED, 0x86, 0x92, 0x8F, 0x15, b'A']);
+    }
+
+    #[test_case]
+    fn box_drawing_and_unmappable() {
+        assert_eq!(to_cp437('┌'), 0xDA);
+        assert_eq!(to_cp437('═'), 0xCD);
+        assert_eq!(to_cp437('€'), 0xFE);
+    }
+
+    #[test_case]
+    fn glyphs_sharing_a_control_byte_are_printed() {
+        crate::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writeln!(writer).unwrap();
+            write!(writer, "◙").unwrap();
+            let (row, column) = writer.cursor();
+            assert_eq!(column, 1);
+            assert_eq!(writer.read_at(row, 0).unwrap().character, 0x0A);
+        });
+    }
+}